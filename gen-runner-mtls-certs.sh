@@ -1,7 +1,32 @@
 #!/bin/bash
 
-echo "TODO"
+# Generates the keypairs for mutual TLS between the switchboard and runners into
+# GRADECOPE_CERTS_DIR, reusing the CA there if there is one:
+#  - the switchboard needs ca.pem and its keypair, switchboard.{pem,key}
+#  - each runner needs ca.pem and its own keypair, runner-<name>.{pem,key}, and must be started
+#    with --tls-ca, --tls-cert and --tls-key pointing at them
+#
+# Usage: gen-runner-mtls-certs.sh <switchboard host name> <runner name>...
 
-# need to generate server keypair + CA keypair + runner keypair
-# then just need to to give server its keypair + CA pubkey, and the runner just needs its own
-# keypair and the server keypair
+SELF_PATH="$0"
+SELF_DIR="$(dirname "${SELF_PATH}")"
+source "${SELF_DIR}/style.sh"
+source "${SELF_DIR}/config.sh"
+
+if [[ $# -lt 1 ]] ; then
+  echo -e "${FERR}: expected the switchboard's host name, then runner names"
+  exit 1
+fi
+
+SERVER_NAME="$1"
+shift
+
+RUNNER_ARGS=()
+for RUNNER in "$@" ; do
+  RUNNER_ARGS+=(--runner "${RUNNER}")
+done
+
+cargo run --release --bin gradecope-switchboard -- gen-certs \
+  --out-dir "${GRADECOPE_CERTS_DIR}" \
+  --server-name "${SERVER_NAME}" \
+  "${RUNNER_ARGS[@]}"
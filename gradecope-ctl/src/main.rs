@@ -7,9 +7,18 @@ use tarpc::{
     tokio_serde::formats::Json,
 };
 
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone as _, Utc};
 use clap::{Parser, Subcommand};
-use colored::Colorize;
-use gradecope_proto::ctl::{CtlClient, JobReference, JobResult, JobStatus};
+use colored::{ColoredString, Colorize};
+use gradecope_proto::admin::{
+    AdminClient, GradebookRow, JobType, JobTypeConfig, Lab, LateStatus, QueuedJob, RunnerStatus,
+    StudentGrades,
+};
+use gradecope_proto::ctl::{
+    Checkoff, CtlClient, CtlError, Grades, JobReference, JobResult, JobStatus, LabGrade, LateDays,
+    PhaseTiming, QueuePosition, TestCase,
+};
+use gradecope_proto::runner::JobPhase;
 use uuid::Uuid;
 
 #[derive(Debug, Parser)]
@@ -17,6 +26,8 @@ use uuid::Uuid;
 struct Opts {
     #[arg(long, default_value = "/var/run/gradecope/gradecope-ctl.sock")]
     ctl_socket_path: String,
+    #[arg(long, default_value = "/var/run/gradecope/gradecope-admin.sock")]
+    admin_socket_path: String,
     #[command(subcommand)]
     command: Commands
 }
@@ -41,11 +52,203 @@ enum Commands {
 	/// Print to stdout instead of using a pager
 	#[arg(long)]
 	no_pager: bool,
+	/// Keep printing the log as the job runs, until it stops
+	#[arg(long, short)]
+	follow: bool,
     },
     Cancel {
 	job_spec: String,
 	id: Uuid
     },
+    /// List released labs and whether you have been checked off on them
+    Grades,
+    /// Staff commands, sent over the admin socket
+    Admin {
+	#[command(subcommand)]
+	command: AdminCommands,
+    },
+}
+
+/// Job type settings; anything not given keeps its current (or default) value
+#[derive(Debug, clap::Args)]
+struct JobTypeArgs {
+    /// Seconds the .run.sh script may run for
+    #[arg(long)]
+    run_timeout: Option<u32>,
+    /// Seconds the .cleanup.sh script may run for
+    #[arg(long)]
+    cleanup_timeout: Option<u32>,
+    /// Maximum size of the job log, in bytes
+    #[arg(long)]
+    max_log_bytes: Option<u32>,
+    /// Only run on devices of this class
+    #[arg(long, conflicts_with = "any_device")]
+    device_class: Option<String>,
+    /// Run on any device
+    #[arg(long)]
+    any_device: bool,
+    #[arg(long, conflicts_with = "disable")]
+    enable: bool,
+    /// Stop accepting submissions
+    #[arg(long)]
+    disable: bool,
+}
+
+impl JobTypeArgs {
+    fn apply(self, config: &mut JobTypeConfig) {
+	if let Some(t) = self.run_timeout { config.run_timeout_secs = t; }
+	if let Some(t) = self.cleanup_timeout { config.cleanup_timeout_secs = t; }
+	if let Some(n) = self.max_log_bytes { config.max_log_bytes = n; }
+	if self.device_class.is_some() { config.device_class = self.device_class; }
+	if self.any_device { config.device_class = None; }
+	if self.enable { config.enabled = true; }
+	if self.disable { config.enabled = false; }
+    }
+}
+
+/// Parses an RFC 3339 timestamp, or a `YYYY-MM-DD HH:MM` one in local time.
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+	return Ok(t.to_utc());
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+	.map_err(|_| format!("expected `YYYY-MM-DD HH:MM` or an RFC 3339 timestamp, got {s:?}"))?;
+    Local.from_local_datetime(&naive)
+	.earliest()
+	.map(|t| t.to_utc())
+	.ok_or_else(|| format!("{s:?} does not exist in the local time zone"))
+}
+
+/// Lab dates; anything not given keeps its current value
+#[derive(Debug, clap::Args)]
+struct LabArgs {
+    /// When the lab becomes visible to students [default for new labs: now]
+    #[arg(long, value_parser = parse_time)]
+    release: Option<DateTime<Utc>>,
+    /// Checkoffs submitted after this are late
+    #[arg(long, value_parser = parse_time, conflicts_with = "no_deadline")]
+    deadline: Option<DateTime<Utc>>,
+    /// Remove the deadline
+    #[arg(long)]
+    no_deadline: bool,
+}
+
+impl LabArgs {
+    fn apply(self, lab: &mut Lab) {
+	if let Some(t) = self.release { lab.released = t; }
+	if self.deadline.is_some() { lab.deadline = self.deadline; }
+	if self.no_deadline { lab.deadline = None; }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ExportFormat {
+    /// One row per user and lab
+    Csv,
+    /// One row per user with a column per lab, for gradebook imports: 1 if checked off on time or
+    /// within the user's late days, 0 otherwise
+    CsvWide,
+    /// An array with one object per user and lab
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum AdminCommands {
+    AddUser {
+	name: String,
+    },
+    RemoveUser {
+	name: String,
+	/// Also delete all of the user's jobs
+	#[arg(long)]
+	purge_jobs: bool,
+    },
+    /// Spawn submission socket listeners for users added outside of `add-user`
+    SyncListeners,
+    AddJobType {
+	spec: String,
+	#[command(flatten)]
+	config: JobTypeArgs,
+    },
+    /// Change the configuration of an existing job type
+    ConfigureJobType {
+	spec: String,
+	#[command(flatten)]
+	config: JobTypeArgs,
+    },
+    /// List job types and their configuration
+    JobTypes,
+    /// Create a lab, checked off by passing the given job type
+    AddLab {
+	name: String,
+	job_spec: String,
+	#[command(flatten)]
+	dates: LabArgs,
+    },
+    /// Change the job type or dates of an existing lab
+    ConfigureLab {
+	name: String,
+	#[arg(long)]
+	job_spec: Option<String>,
+	#[command(flatten)]
+	dates: LabArgs,
+    },
+    /// List all labs, including unreleased ones
+    Labs,
+    /// Set how many late days a user may spend
+    SetLateDays {
+	user: String,
+	#[arg(required_unless_present = "default")]
+	days: Option<u32>,
+	/// Use the switchboard's default budget
+	#[arg(long, conflicts_with = "days")]
+	default: bool,
+    },
+    /// Give a user their own deadline for a lab
+    Extend {
+	user: String,
+	lab: String,
+	#[arg(value_parser = parse_time)]
+	deadline: DateTime<Utc>,
+    },
+    /// Put a user back on a lab's regular deadline
+    RevokeExtension {
+	user: String,
+	lab: String,
+    },
+    /// Show checkoffs and late days of the given users, or of everyone
+    Grades {
+	users: Vec<String>,
+    },
+    /// Write the gradebook of the given users, or of everyone, to stdout
+    Export {
+	#[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+	format: ExportFormat,
+	/// Only export this lab; may be given more than once
+	#[arg(long = "lab")]
+	labs: Vec<String>,
+	users: Vec<String>,
+    },
+    /// List queued and running jobs
+    Queue,
+    /// List runners and their devices
+    Runners,
+    Cancel {
+	id: Uuid,
+	#[arg(long, default_value = "canceled by staff")]
+	reason: String,
+    },
+    Requeue {
+	id: Uuid,
+    },
+    /// Have a runner finish its running jobs without taking new ones, and then exit
+    Drain {
+	runner: String,
+    },
+}
+
+fn format_time(t: &DateTime<Utc>) -> String {
+    t.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string()
 }
 
 fn format_result(result: &JobResult) -> String {
@@ -66,10 +269,119 @@ fn last_n_lines(text: &str, n: usize) -> String {
     lines[start..].join("\n")
 }
 
-fn print_job_status(status: &JobStatus, log_preview: Option<&str>) {
+fn format_points(points: f64) -> String {
+    // drop the fractional part for whole points, which is the common case
+    if points.fract() == 0.0 {
+	format!("{points:.0}")
+    } else {
+	format!("{points:.2}")
+    }
+}
+
+fn print_test_cases(test_cases: &[TestCase]) {
+    let max_name_width = test_cases.iter().map(|t| t.name.len()).max().unwrap_or(0);
+
+    for test in test_cases {
+	let mark = if test.passed { "✓".green() } else { "✗".red() };
+	let points = match (test.points, test.max_points) {
+	    (Some(p), Some(max)) => format!("{}/{}", format_points(p), format_points(max)),
+	    (Some(p), None) => format_points(p),
+	    (None, Some(max)) => format!("-/{}", format_points(max)),
+	    (None, None) => String::new(),
+	};
+	print!("{mark} {:width$}  {:>9}", test.name, points, width = max_name_width);
+	if let Some(message) = &test.message {
+	    print!("  {}", message.dimmed());
+	}
+	println!();
+    }
+
+    let passed = test_cases.iter().filter(|t| t.passed).count();
+    let mut summary = format!("{passed}/{} passed", test_cases.len());
+    if test_cases.iter().any(|t| t.points.is_some()) {
+	let points: f64 = test_cases.iter().filter_map(|t| t.points).sum();
+	summary.push_str(&format!(", {} points", format_points(points)));
+	if test_cases.iter().all(|t| t.max_points.is_some()) {
+	    let max_points: f64 = test_cases.iter().filter_map(|t| t.max_points).sum();
+	    summary.push_str(&format!(" of {}", format_points(max_points)));
+	}
+    }
+    println!("{}", summary.bold());
+}
+
+fn format_phase(phase: JobPhase) -> &'static str {
+    match phase {
+	JobPhase::Started => "Started",
+	JobPhase::RepoFetched => "Repo fetched",
+	JobPhase::BuildDone => "Build done",
+	JobPhase::DeviceFlashed => "Device flashed",
+	JobPhase::TestsDone => "Tests done",
+	JobPhase::CleanupDone => "Cleanup done",
+    }
+}
+
+/// Prints when a job reached each phase, and how long each took since the one before.
+fn print_phases(phases: &[PhaseTiming]) {
+    let mut previous: Option<DateTime<Utc>> = None;
+    for timing in phases {
+	let time = timing.at.with_timezone(&Local).format("%H:%M:%S");
+	let took = previous
+	    .map(|previous| format!("(+{}s)", (timing.at - previous).num_seconds()))
+	    .unwrap_or_default();
+	println!("  {:14}  {time}  {}", format_phase(timing.phase), took.dimmed());
+	previous = Some(timing.at);
+    }
+}
+
+/// Prints where a pending job stands in the queue, and roughly when it will start.
+fn print_queue_position(queue: &QueuePosition) {
+    let devices = match queue.active_devices {
+	1 => "1 device".to_owned(),
+	n => format!("{n} devices"),
+    };
+    println!("{}   #{} in line, {devices} available", "Queue:".bold(), queue.position);
+
+    let eta = match queue.estimated_start {
+	_ if queue.active_devices == 0 => "unknown, no devices are available right now".dimmed(),
+	None => "unknown, no recent runs to go by".dimmed(),
+	Some(start) => {
+	    let mins = ((start - Utc::now()).num_seconds() + 59) / 60;
+	    let wait = match mins {
+		..=0 => "any moment now".to_owned(),
+		1 => "in about a minute".to_owned(),
+		_ => format!("in about {mins} minutes"),
+	    };
+	    format!("~{} ({wait})", start.with_timezone(&Local).format("%H:%M")).normal()
+	}
+    };
+    println!("{}     {eta}", "ETA:".bold());
+}
+
+fn print_job_status(
+    status: &JobStatus,
+    queue: Option<&QueuePosition>,
+    phases: &[PhaseTiming],
+    test_cases: &[TestCase],
+    log_preview: Option<&str>,
+) {
     println!("{}    {}", "Spec:".bold(), status.job_spec);
     println!("{}      {}", "ID:".bold(), status.job_id);
     println!("{}  {}", "Status:".bold(), format_result(&status.result));
+    if let Some(queue) = queue {
+	print_queue_position(queue);
+    }
+
+    if !phases.is_empty() {
+	println!();
+	println!("{}", "Phases:".bold().underline());
+	print_phases(phases);
+    }
+
+    if !test_cases.is_empty() {
+	println!();
+	println!("{}", "Tests:".bold().underline());
+	print_test_cases(test_cases);
+    }
 
     if let Some(log) = log_preview {
 	println!();
@@ -98,13 +410,455 @@ fn print_job_table(jobs: &[JobStatus]) {
 	println!(
 	    "{:width$}  {}  {}",
 	    job.job_spec.bold(),
-	    &job.job_id.to_string()[..8].dimmed(),
+	    job.job_id.to_string()[..8].dimmed(),
+	    format_result(&job.result),
+	    width = max_spec_width
+	);
+    }
+}
+
+fn print_queue(jobs: &[QueuedJob]) {
+    if jobs.is_empty() {
+	println!("{}", "Queue is empty.".dimmed());
+	return;
+    }
+
+    let max_user_width = jobs.iter().map(|j| j.user.len()).max().unwrap_or(0).max(4);
+    let max_spec_width = jobs.iter().map(|j| j.job_spec.len()).max().unwrap_or(0).max(3);
+
+    println!(
+	"{:uwidth$}  {:swidth$}  {:36}  {:16}  {}",
+	"USER".bold().underline(),
+	"JOB".bold().underline(),
+	"ID".bold().underline(),
+	"SUBMITTED".bold().underline(),
+	"STATUS".bold().underline(),
+	uwidth = max_user_width,
+	swidth = max_spec_width
+    );
+
+    for job in jobs {
+	println!(
+	    "{:uwidth$}  {:swidth$}  {}  {}  {}",
+	    job.user,
+	    job.job_spec.bold(),
+	    job.job_id.to_string().dimmed(),
+	    format_time(&job.submitted),
 	    format_result(&job.result),
+	    uwidth = max_user_width,
+	    swidth = max_spec_width
+	);
+    }
+}
+
+fn print_job_types(job_types: &[JobType]) {
+    if job_types.is_empty() {
+	println!("{}", "No job types.".dimmed());
+	return;
+    }
+
+    let max_spec_width = job_types.iter().map(|j| j.spec.len()).max().unwrap_or(0).max(3);
+
+    println!(
+	"{:width$}  {:>7}  {:>7}  {:>9}  {:12}  {}",
+	"JOB".bold().underline(),
+	"RUN".bold().underline(),
+	"CLEANUP".bold().underline(),
+	"LOG".bold().underline(),
+	"DEVICE".bold().underline(),
+	"ENABLED".bold().underline(),
+	width = max_spec_width
+    );
+
+    for jt in job_types {
+	let c = &jt.config;
+	println!(
+	    "{:width$}  {:>6}s  {:>6}s  {:>9}  {:12}  {}",
+	    jt.spec.bold(),
+	    c.run_timeout_secs,
+	    c.cleanup_timeout_secs,
+	    c.max_log_bytes,
+	    c.device_class.as_deref().unwrap_or("any"),
+	    if c.enabled { "yes".green() } else { "no".red() },
 	    width = max_spec_width
 	);
     }
 }
 
+fn print_labs(labs: &[Lab]) {
+    if labs.is_empty() {
+	println!("{}", "No labs.".dimmed());
+	return;
+    }
+
+    let max_name_width = labs.iter().map(|l| l.name.len()).max().unwrap_or(0).max(3);
+    let max_spec_width = labs.iter().map(|l| l.job_spec.len()).max().unwrap_or(0).max(3);
+
+    println!(
+	"{:nwidth$}  {:swidth$}  {:16}  {}",
+	"LAB".bold().underline(),
+	"JOB".bold().underline(),
+	"RELEASED".bold().underline(),
+	"DEADLINE".bold().underline(),
+	nwidth = max_name_width,
+	swidth = max_spec_width
+    );
+
+    let now = Utc::now();
+    for lab in labs {
+	let released = format_time(&lab.released);
+	println!(
+	    "{:nwidth$}  {:swidth$}  {:16}  {}",
+	    lab.name.bold(),
+	    lab.job_spec,
+	    if lab.released <= now { released.normal() } else { released.dimmed() },
+	    lab.deadline.as_ref().map(format_time).unwrap_or_else(|| "none".to_owned()),
+	    nwidth = max_name_width,
+	    swidth = max_spec_width
+	);
+    }
+}
+
+fn format_checkoff(checkoff: Option<&Checkoff>) -> ColoredString {
+    match checkoff {
+	Some(c) if c.on_time() => "✓ on time".green(),
+	Some(c) if c.excused => format!("✓ {}d late", c.days_late).yellow(),
+	Some(c) => format!("✓ {}d late!", c.days_late).red(),
+	None => "✗ not yet".red(),
+    }
+}
+
+fn format_deadline(grade: &LabGrade) -> String {
+    match &grade.deadline {
+	Some(t) if grade.extended => format!("{}*", format_time(t)),
+	Some(t) => format_time(t),
+	None => "none".to_owned(),
+    }
+}
+
+fn print_grades(grades: &Grades) {
+    if grades.labs.is_empty() {
+	println!("{}", "No labs have been released yet.".dimmed());
+	return;
+    }
+
+    let max_name_width = grades.labs.iter().map(|g| g.lab.len()).max().unwrap_or(0).max(3);
+    let max_spec_width = grades.labs.iter().map(|g| g.job_spec.len()).max().unwrap_or(0).max(3);
+
+    println!(
+	"{:nwidth$}  {:swidth$}  {:17}  {:11}  {}",
+	"LAB".bold().underline(),
+	"JOB".bold().underline(),
+	"DEADLINE".bold().underline(),
+	"CHECKOFF".bold().underline(),
+	"BEST RESULT".bold().underline(),
+	nwidth = max_name_width,
+	swidth = max_spec_width
+    );
+
+    for grade in &grades.labs {
+	let best = match &grade.best_result {
+	    Some(result) => format_result(result),
+	    None => "—".dimmed().to_string(),
+	};
+	println!(
+	    "{:nwidth$}  {:swidth$}  {:17}  {:11}  {}",
+	    grade.lab.bold(),
+	    grade.job_spec,
+	    format_deadline(grade),
+	    format_checkoff(grade.checkoff.as_ref()),
+	    best,
+	    nwidth = max_name_width,
+	    swidth = max_spec_width
+	);
+    }
+
+    println!();
+    if grades.labs.iter().any(|g| g.extended) {
+	println!("{}", "* extended deadline".dimmed());
+    }
+    if grades.labs.iter().any(|g| g.checkoff.as_ref().is_some_and(|c| !c.excused)) {
+	println!("{}", "! not covered by late days".dimmed());
+    }
+    let LateDays { budget, used } = grades.late_days;
+    println!("{} {used} of {budget} used", "Late days:".bold());
+}
+
+fn print_student_grades(students: &[StudentGrades]) {
+    let rows: Vec<(&str, &LabGrade)> = students
+	.iter()
+	.flat_map(|s| s.grades.labs.iter().map(|g| (s.user.as_str(), g)))
+	.collect();
+    if rows.is_empty() {
+	println!("{}", "No released labs.".dimmed());
+    } else {
+	let max_user_width = rows.iter().map(|(u, _)| u.len()).max().unwrap_or(0).max(4);
+	let max_name_width = rows.iter().map(|(_, g)| g.lab.len()).max().unwrap_or(0).max(3);
+
+	println!(
+	    "{:uwidth$}  {:nwidth$}  {:17}  {}",
+	    "USER".bold().underline(),
+	    "LAB".bold().underline(),
+	    "DEADLINE".bold().underline(),
+	    "CHECKOFF".bold().underline(),
+	    uwidth = max_user_width,
+	    nwidth = max_name_width
+	);
+	for (user, grade) in rows {
+	    println!(
+		"{:uwidth$}  {:nwidth$}  {:17}  {}",
+		user,
+		grade.lab.bold(),
+		format_deadline(grade),
+		format_checkoff(grade.checkoff.as_ref()),
+		uwidth = max_user_width,
+		nwidth = max_name_width
+	    );
+	}
+    }
+
+    println!();
+    let max_user_width = students.iter().map(|s| s.user.len()).max().unwrap_or(0).max(4);
+    println!(
+	"{:uwidth$}  {}",
+	"USER".bold().underline(),
+	"LATE DAYS".bold().underline(),
+	uwidth = max_user_width
+    );
+    for student in students {
+	let LateDays { budget, used } = student.grades.late_days;
+	println!("{:uwidth$}  {used}/{budget}", student.user, uwidth = max_user_width);
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+	format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+	field.to_owned()
+    }
+}
+
+fn csv_line<S: AsRef<str>>(fields: impl IntoIterator<Item = S>) -> String {
+    fields.into_iter().map(|f| csv_field(f.as_ref())).collect::<Vec<_>>().join(",") + "\n"
+}
+
+fn late_status_name(status: LateStatus) -> &'static str {
+    match status {
+	LateStatus::OnTime => "on_time",
+	LateStatus::Excused => "excused",
+	LateStatus::Late => "late",
+	LateStatus::NotCheckedOff => "not_checked_off",
+    }
+}
+
+fn format_gradebook(rows: &[GradebookRow], format: ExportFormat) -> eyre::Result<String> {
+    let mut out = String::new();
+    match format {
+	ExportFormat::Csv => {
+	    out.push_str(&csv_line([
+		"user", "lab", "job_spec", "best_result", "first_pass", "attempts", "days_late", "late_status",
+	    ]));
+	    for row in rows {
+		out.push_str(&csv_line([
+		    row.user.clone(),
+		    row.lab.clone(),
+		    row.job_spec.clone(),
+		    row.best_result.as_ref().map(|r| format!("{r:?}")).unwrap_or_default(),
+		    row.first_pass.map(|t| t.to_rfc3339()).unwrap_or_default(),
+		    row.attempts.to_string(),
+		    row.days_late.to_string(),
+		    late_status_name(row.late_status).to_owned(),
+		]));
+	    }
+	}
+	ExportFormat::CsvWide => {
+	    // rows come grouped by user, with labs in the same order for everyone
+	    let mut labs: Vec<&str> = vec![];
+	    for row in rows {
+		if !labs.contains(&row.lab.as_str()) {
+		    labs.push(&row.lab);
+		}
+	    }
+	    out.push_str(&csv_line(std::iter::once("user").chain(labs.iter().copied())));
+	    for user_rows in rows.chunk_by(|a, b| a.user == b.user) {
+		let scores = labs.iter().map(|lab| {
+		    let passed = user_rows.iter().any(|row| {
+			row.lab == *lab && matches!(row.late_status, LateStatus::OnTime | LateStatus::Excused)
+		    });
+		    if passed { "1" } else { "0" }
+		});
+		out.push_str(&csv_line(std::iter::once(user_rows[0].user.as_str()).chain(scores)));
+	    }
+	}
+	ExportFormat::Json => {
+	    out = serde_json::to_string_pretty(rows)?;
+	    out.push('\n');
+	}
+    }
+    Ok(out)
+}
+
+fn print_runners(runners: &[RunnerStatus]) {
+    if runners.is_empty() {
+	println!("{}", "No runners have registered.".dimmed());
+	return;
+    }
+
+    for (i, runner) in runners.iter().enumerate() {
+	if i > 0 {
+	    println!();
+	}
+	let state = if runner.draining {
+	    format!("draining, connected from {}", runner.peer_addr).yellow()
+	} else if runner.connected {
+	    format!("connected from {}", runner.peer_addr).green()
+	} else {
+	    format!("last seen {} from {}", format_time(&runner.last_seen), runner.peer_addr).dimmed()
+	};
+	println!("{} ({}) {}", runner.name.bold(), runner.version, state);
+	println!(
+	    "  {} run, {} errored; runs {}",
+	    runner.jobs_run,
+	    runner.jobs_errored,
+	    if runner.job_specs.is_empty() { "nothing".to_owned() } else { runner.job_specs.join(", ") }
+	);
+
+	let port = |bus: u8, ports: &[u8]| {
+	    let ports: Vec<String> = ports.iter().map(u8::to_string).collect();
+	    format!("{bus}-{}", ports.join("."))
+	};
+	let max_serial_width = runner.devices.iter().map(|d| d.serial.len()).max().unwrap_or(0).max(6);
+	let max_port_width = runner.devices.iter()
+	    .map(|d| port(d.usb_bus, &d.usb_ports).len())
+	    .max().unwrap_or(0).max(4);
+	println!(
+	    "  {:swidth$}  {:pwidth$}  {:10}  {:>4}  {:>7}  {}",
+	    "SERIAL".bold().underline(),
+	    "PORT".bold().underline(),
+	    "CLASS".bold().underline(),
+	    "RUN".bold().underline(),
+	    "ERRORED".bold().underline(),
+	    "STATUS".bold().underline(),
+	    swidth = max_serial_width,
+	    pwidth = max_port_width
+	);
+	for device in &runner.devices {
+	    let status = match (&device.current_job, &device.quarantine_reason, device.connected) {
+		(Some(job_id), _, _) => format!("running {job_id}").cyan(),
+		(None, Some(reason), true) => format!("quarantined: {reason}").red(),
+		(None, None, true) => "idle".green(),
+		(None, _, false) => "disconnected".dimmed(),
+	    };
+	    println!(
+		"  {:swidth$}  {:pwidth$}  {:10}  {:>4}  {:>7}  {}",
+		device.serial,
+		port(device.usb_bus, &device.usb_ports),
+		device.class.as_deref().unwrap_or("-"),
+		device.jobs_run,
+		device.jobs_errored,
+		status,
+		swidth = max_serial_width,
+		pwidth = max_port_width
+	    );
+	}
+    }
+}
+
+/// Runs an admin command. Unlike the student commands, failures exit non-zero so that the
+/// provisioning scripts can tell when something went wrong.
+async fn run_admin(admin_socket_path: String, command: AdminCommands) -> eyre::Result<()> {
+    let transport = unix::connect(admin_socket_path, Json::default).await?;
+    let client = AdminClient::new(client::Config::default(), transport).spawn();
+
+    let res = match command {
+	AdminCommands::AddUser { name } => client.add_user(context::current(), name.clone()).await?
+	    .map(|id| println!("Added user {} ({})", name.bold(), id.to_string().dimmed())),
+	AdminCommands::RemoveUser { name, purge_jobs } => client.remove_user(context::current(), name.clone(), purge_jobs).await?
+	    .map(|()| println!("Removed user {}", name.bold())),
+	AdminCommands::SyncListeners => client.sync_listeners(context::current()).await?
+	    .map(|n| println!("Spawned {n} submission socket listener(s)")),
+	AdminCommands::AddJobType { spec, config: args } => {
+	    let mut config = JobTypeConfig::default();
+	    args.apply(&mut config);
+	    client.create_job_type(context::current(), spec.clone(), config).await?
+		.map(|id| println!("Created job type {} ({})", spec.bold(), id.to_string().dimmed()))
+	}
+	AdminCommands::ConfigureJobType { spec, config: args } => {
+	    match client.job_types(context::current()).await? {
+		Ok(job_types) => match job_types.into_iter().find(|jt| jt.spec == spec) {
+		    Some(JobType { mut config, .. }) => {
+			args.apply(&mut config);
+			client.configure_job_type(context::current(), spec.clone(), config).await?
+			    .map(|()| println!("Updated job type {}", spec.bold()))
+		    }
+		    None => Err(CtlError::NotFound(format!("Job type {spec} not found"))),
+		},
+		Err(e) => Err(e),
+	    }
+	}
+	AdminCommands::JobTypes => client.job_types(context::current()).await?
+	    .map(|job_types| print_job_types(&job_types)),
+	AdminCommands::AddLab { name, job_spec, dates } => {
+	    let mut lab = Lab { name: name.clone(), job_spec, released: Utc::now(), deadline: None };
+	    dates.apply(&mut lab);
+	    client.create_lab(context::current(), lab).await?
+		.map(|id| println!("Created lab {} ({})", name.bold(), id.to_string().dimmed()))
+	}
+	AdminCommands::ConfigureLab { name, job_spec, dates } => {
+	    match client.labs(context::current()).await? {
+		Ok(labs) => match labs.into_iter().find(|l| l.name == name) {
+		    Some(mut lab) => {
+			if let Some(job_spec) = job_spec { lab.job_spec = job_spec; }
+			dates.apply(&mut lab);
+			client.configure_lab(context::current(), lab).await?
+			    .map(|()| println!("Updated lab {}", name.bold()))
+		    }
+		    None => Err(CtlError::NotFound(format!("Lab {name} not found"))),
+		},
+		Err(e) => Err(e),
+	    }
+	}
+	AdminCommands::Labs => client.labs(context::current()).await?
+	    .map(|labs| print_labs(&labs)),
+	AdminCommands::SetLateDays { user, days, default: _ } => client.set_late_day_budget(context::current(), user.clone(), days).await?
+	    .map(|()| match days {
+		Some(days) => println!("{} may now use {days} late day(s)", user.bold()),
+		None => println!("{} now has the default late day budget", user.bold()),
+	    }),
+	AdminCommands::Extend { user, lab, deadline } => client.grant_extension(context::current(), user.clone(), lab.clone(), deadline).await?
+	    .map(|()| println!("Extended {} for {} to {}", lab.bold(), user.bold(), format_time(&deadline))),
+	AdminCommands::RevokeExtension { user, lab } => client.revoke_extension(context::current(), user.clone(), lab.clone()).await?
+	    .map(|()| println!("Revoked extension of {} for {}", lab.bold(), user.bold())),
+	AdminCommands::Grades { users } => client.grades(context::current(), users).await?
+	    .map(|students| print_student_grades(&students)),
+	AdminCommands::Export { format, labs, users } => match client.gradebook(context::current(), users, labs).await? {
+	    Ok(rows) => {
+		io::stdout().write_all(format_gradebook(&rows, format)?.as_bytes())?;
+		Ok(())
+	    }
+	    Err(e) => Err(e),
+	},
+	AdminCommands::Runners => client.runners(context::current()).await?
+	    .map(|runners| print_runners(&runners)),
+	AdminCommands::Queue => client.queue(context::current()).await?
+	    .map(|jobs| print_queue(&jobs)),
+	AdminCommands::Cancel { id, reason } => client.force_cancel(context::current(), id, reason).await?
+	    .map(|status| print_job_status(&status, None, &[], &[], None)),
+	AdminCommands::Requeue { id } => client.requeue(context::current(), id).await?
+	    .map(|status| print_job_status(&status, None, &[], &[], None)),
+	AdminCommands::Drain { runner } => client.drain_runner(context::current(), runner.clone()).await?
+	    .map(|()| println!("Draining {}; it will exit once its running jobs are done", runner.bold())),
+    };
+
+    if let Err(e) = res {
+	print_error(e);
+	std::process::exit(1);
+    }
+
+    Ok(())
+}
+
 fn show_in_pager(content: &[u8]) -> io::Result<()> {
     for pager in ["less", "more"] {
 	if let Ok(mut child) = Command::new(pager).stdin(Stdio::piped()).spawn() {
@@ -126,6 +880,10 @@ fn print_error(e: impl std::fmt::Display) {
 async fn main() -> eyre::Result<()> {
     let opts = Opts::parse();
 
+    if let Commands::Admin { command } = opts.command {
+	return run_admin(opts.admin_socket_path, command).await;
+    }
+
     let transport = unix::connect(opts.ctl_socket_path, Json::default).await?;
     let client = CtlClient::new(client::Config::default(), transport).spawn();
 
@@ -143,19 +901,67 @@ async fn main() -> eyre::Result<()> {
 	    let job_ref = JobReference { job_spec, job_id: id };
 	    match client.status(context::current(), job_ref.clone()).await? {
 		Ok(status) => {
+		    let queue = match status.result {
+			JobResult::Pending => client.queue_position(context::current(), job_ref.clone()).await
+			    .ok()
+			    .and_then(|r| r.ok())
+			    .flatten(),
+			_ => None,
+		    };
+		    let phases = client.phases(context::current(), job_ref.clone()).await
+			.ok()
+			.and_then(|r| r.ok())
+			.unwrap_or_default();
+		    let test_cases = client.test_cases(context::current(), job_ref.clone()).await
+			.ok()
+			.and_then(|r| r.ok())
+			.unwrap_or_default();
 		    let log_preview = client.log(context::current(), job_ref).await
 			.ok()
 			.and_then(|r| r.ok())
 			.map(|log| String::from_utf8_lossy(&log.log).into_owned())
 			.filter(|s| !s.is_empty())
 			.map(|s| last_n_lines(&s, 10));
-		    print_job_status(&status, log_preview.as_deref());
+		    print_job_status(&status, queue.as_ref(), &phases, &test_cases, log_preview.as_deref());
 		}
 		Err(e) => print_error(e),
 	    }
 	}
 
-	Commands::Log { job_spec, id, no_pager } => {
+	Commands::Log { job_spec, id, follow: true, .. } => {
+	    let job_ref = JobReference { job_spec, job_id: id };
+	    let mut offset = 0u64;
+	    let mut attempt = None;
+	    loop {
+		match client.tail_log(context::current(), job_ref.clone(), offset).await? {
+		    // The job was put back in the queue, and its log starts over
+		    Ok(tail) if attempt.is_some_and(|attempt| attempt != tail.attempt) => {
+			if offset > 0 {
+			    println!("\n{}", "===== job was requeued, log starts over =====".dimmed());
+			}
+			attempt = Some(tail.attempt);
+			offset = 0;
+			continue;
+		    }
+		    Ok(tail) => {
+			attempt = Some(tail.attempt);
+			io::stdout().write_all(&tail.data)?;
+			io::stdout().flush()?;
+			offset += tail.data.len() as u64;
+			if tail.done {
+			    break;
+			}
+		    }
+		    Err(e) => {
+			print_error(e);
+			break;
+		    }
+		}
+		tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+	    }
+	}
+
+	Commands::Log { job_spec, id, no_pager, follow: false } => {
 	    let job_ref = JobReference { job_spec, job_id: id };
 	    match client.log(context::current(), job_ref).await? {
 		Ok(log) if log.log.is_empty() => println!("{}", "No log available.".dimmed()),
@@ -165,8 +971,106 @@ async fn main() -> eyre::Result<()> {
 	    }
 	}
 
-	_ => eprintln!("{}", "Not implemented yet.".yellow()),
+	Commands::Cancel { job_spec, id } => {
+	    let job_ref = JobReference { job_spec, job_id: id };
+	    match client.cancel(context::current(), job_ref).await? {
+		Ok(status) => {
+		    if let JobResult::Running = status.result {
+			println!("{}", "Cancellation requested; the runner will stop this job shortly.".yellow());
+			println!();
+		    }
+		    print_job_status(&status, None, &[], &[], None);
+		}
+		Err(e) => print_error(e),
+	    }
+	}
+
+	Commands::Grades => {
+	    match client.grades(context::current()).await? {
+		Ok(grades) => print_grades(&grades),
+		Err(e) => print_error(e),
+	    }
+	}
+
+	Commands::Submit { job_spec, commit } => {
+	    match client.submit(context::current(), commit, job_spec.clone()).await? {
+		Ok(job_id) => {
+		    println!("Submitted job {}", job_id.to_string().green().bold());
+		    println!(
+			"{}",
+			format!("Check on it with `gradecope-ctl status {job_spec} {job_id}`").dimmed()
+		    );
+		}
+		Err(e) => print_error(e),
+	    }
+	}
+
+	Commands::Admin { .. } => unreachable!("admin commands are handled before connecting"),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(user: &str, lab: &str, late_status: LateStatus) -> GradebookRow {
+	GradebookRow {
+	    user: user.to_owned(),
+	    lab: lab.to_owned(),
+	    job_spec: format!("{lab}-spec"),
+	    best_result: Some(JobResult::Completed),
+	    first_pass: None,
+	    attempts: 2,
+	    days_late: 0,
+	    late_status,
+	}
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+	assert_eq!(csv_field("plain"), "plain");
+	assert_eq!(csv_field(""), "");
+	assert_eq!(csv_field("a,b"), "\"a,b\"");
+	assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+	assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+	assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+	assert_eq!(csv_line(["a", "b,c", ""]), "a,\"b,c\",\n");
+    }
+
+    #[test]
+    fn csv_export_has_one_line_per_row() {
+	let rows = [row("alice", "lab1", LateStatus::OnTime), row("bob, jr", "lab1", LateStatus::Late)];
+	assert_eq!(
+	    format_gradebook(&rows, ExportFormat::Csv).unwrap(),
+	    "user,lab,job_spec,best_result,first_pass,attempts,days_late,late_status\n\
+	     alice,lab1,lab1-spec,Completed,,2,0,on_time\n\
+	     \"bob, jr\",lab1,lab1-spec,Completed,,2,0,late\n"
+	);
+    }
+
+    #[test]
+    fn wide_csv_export_counts_on_time_and_excused_checkoffs() {
+	let rows = [
+	    row("alice", "lab1", LateStatus::OnTime),
+	    row("alice", "lab2", LateStatus::Late),
+	    row("bob", "lab1", LateStatus::Excused),
+	    row("bob", "lab2", LateStatus::NotCheckedOff),
+	];
+	assert_eq!(
+	    format_gradebook(&rows, ExportFormat::CsvWide).unwrap(),
+	    "user,lab1,lab2\nalice,1,0\nbob,1,0\n"
+	);
+    }
+
+    #[test]
+    fn json_export_round_trips() {
+	let rows = [row("alice", "lab1", LateStatus::Excused)];
+	let json = format_gradebook(&rows, ExportFormat::Json).unwrap();
+	let parsed: Vec<GradebookRow> = serde_json::from_str(&json).unwrap();
+	assert_eq!(parsed.len(), 1);
+	assert_eq!(parsed[0].user, "alice");
+	assert_eq!(parsed[0].late_status, LateStatus::Excused);
+    }
+}
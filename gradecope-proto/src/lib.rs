@@ -12,6 +12,14 @@ pub mod runner {
         pub commit_hash: String,
         /// Job spec
         pub job_spec: String,
+        /// Seconds the `.run.sh` script may run for
+        pub run_timeout_secs: u32,
+        /// Seconds the `.cleanup.sh` script may run for
+        pub cleanup_timeout_secs: u32,
+        /// Maximum number of bytes of log to send back
+        pub max_log_bytes: u32,
+        /// Class of device the job must run on, if any
+        pub device_class: Option<String>,
     }
 
     #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -27,6 +35,9 @@ pub mod runner {
         Error,
         Canceled,
         Timeout,
+        /// The device misbehaved while running the job, and was quarantined. The job is put back
+        /// in the queue.
+        DeviceFailure,
     }
 
     #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -35,32 +46,213 @@ pub mod runner {
         pub truncated: bool,
     }
 
+    /// Part of the log of a job that is still running.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct LogChunk {
+        pub job_id: uuid::Uuid,
+        /// Position of `data` in the job's log
+        pub offset: u64,
+        pub data: Vec<u8>,
+    }
+
+    /// One entry of the results file a test script may write to `RESULTS_PATH`, which is a JSON
+    /// array of these.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct TestCase {
+        pub name: String,
+        pub passed: bool,
+        #[serde(default)]
+        pub points: Option<f64>,
+        #[serde(default)]
+        pub max_points: Option<f64>,
+        #[serde(default)]
+        pub message: Option<String>,
+    }
+
+    /// A milestone in running a job. The runner reports when a job starts and when its scripts
+    /// exit; the others are reported by the `.run.sh` script, if it reports them at all.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum JobPhase {
+        /// The device was prepared and the `.run.sh` script started
+        Started,
+        RepoFetched,
+        BuildDone,
+        DeviceFlashed,
+        /// The `.run.sh` script exited
+        TestsDone,
+        /// The `.cleanup.sh` script exited
+        CleanupDone,
+    }
+
+    /// When a running job reached a [`JobPhase`], by the runner's clock.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct PhaseEvent {
+        pub job_id: uuid::Uuid,
+        pub phase: JobPhase,
+        pub at: DateTime<Utc>,
+    }
+
     #[derive(Debug, Clone, Deserialize, Serialize)]
     pub struct JobTermination {
         pub job_id: uuid::Uuid,
+        /// The device the job ran on, if it got as far as being given one
+        pub device_id: Option<uuid::Uuid>,
         pub log: Log,
         pub result: JobResult,
+        /// What went wrong with the device, with a `DeviceFailure` result
+        pub device_failure: Option<String>,
+        /// Empty if the test script didn't write a results file
+        pub test_cases: Vec<TestCase>,
+        /// Every phase the job reached, including those already reported with `job_phase`, which
+        /// may have been lost to a disconnection
+        pub phases: Vec<PhaseEvent>,
+        /// When the job stopped
         pub now: DateTime<Utc>,
+        /// Why the runner canceled the job, if it did so of its own accord rather than because the
+        /// switchboard told it to
+        pub cancel_reason: Option<String>,
+    }
+    impl JobTermination {
+        /// A job that stopped just now without a log or any results, e.g. because it couldn't be
+        /// set up or its device failed.
+        pub fn failed(job_id: uuid::Uuid, device_id: Option<uuid::Uuid>, result: JobResult) -> Self {
+            Self {
+                job_id,
+                device_id,
+                log: Log { log: vec![], truncated: false },
+                result,
+                device_failure: None,
+                test_cases: vec![],
+                phases: vec![],
+                now: Utc::now(),
+                cancel_reason: None,
+            }
+        }
+
+        /// A job that stopped just now because its device failed for the given reason.
+        pub fn device_failed(job_id: uuid::Uuid, device_id: Option<uuid::Uuid>, reason: String) -> Self {
+            Self {
+                device_failure: Some(reason),
+                ..Self::failed(job_id, device_id, JobResult::DeviceFailure)
+            }
+        }
+    }
+
+    const ID_NAMESPACE: uuid::Uuid = uuid::Uuid::from_u128(0x6772_6164_6563_6f70_6500_7275_6e6e_6572);
+
+    /// The ID a runner is known by, derived from its `--id` so that it survives restarts.
+    pub fn runner_id(name: &str) -> uuid::Uuid {
+        uuid::Uuid::new_v5(&ID_NAMESPACE, name.as_bytes())
+    }
+
+    /// The ID of the device a runner has on the given USB port.
+    pub fn device_id(runner_name: &str, usb_bus: u8, usb_ports: &[u8]) -> uuid::Uuid {
+        let ports: Vec<String> = usb_ports.iter().map(u8::to_string).collect();
+        let port = format!("{usb_bus}-{}", ports.join("."));
+        uuid::Uuid::new_v5(&runner_id(runner_name), port.as_bytes())
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct DeviceInfo {
+        /// Stable across runner restarts, as long as the device stays on the same USB port
+        pub id: uuid::Uuid,
+        /// Path to the device's serial port
+        pub serial: String,
+        pub usb_bus: u8,
+        pub usb_ports: Vec<u8>,
+        pub class: Option<String>,
+    }
+
+    /// What a runner tells the switchboard about itself when it connects.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct RunnerRegistration {
+        /// The runner's `--id`, which must be unique among connected runners
+        pub name: String,
+        pub version: String,
+        pub devices: Vec<DeviceInfo>,
+        /// Job specs that have a `.run.sh` script in the runner's `--test-runner` directory
+        pub job_specs: Vec<String>,
+        /// Jobs the runner took on a previous connection and is still running, or has yet to
+        /// report the termination of. Any other job the switchboard thinks the runner has is
+        /// recovered.
+        pub unfinished_jobs: Vec<uuid::Uuid>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub enum RegistrationResponse {
+        Accepted,
+        Rejected(String),
+    }
+
+    /// What a runner waiting for work is woken up for. Empty if nothing happened in time.
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct WorkNotification {
+        /// There are jobs the runner's idle devices can run; it should `request_job` them
+        pub jobs_available: bool,
+        /// Jobs the runner is running that should be canceled
+        pub cancellations: Vec<uuid::Uuid>,
+        /// The switchboard is shutting down: the runner should stop waiting for jobs, and only
+        /// report on the jobs it is running until it reconnects
+        pub draining: bool,
+        /// Staff asked the runner to drain: it should take no more jobs, and exit once it has
+        /// reported on the jobs it is running
+        pub drain_requested: bool,
     }
 
     #[tarpc::service]
     pub trait Switchboard {
-        /// Request a job from the switchboard.
-        async fn request_job() -> JobResponse;
+        /// Identify the runner to the switchboard. Must be called once, before any other call;
+        /// until then, no jobs are handed out.
+        async fn register(registration: RunnerRegistration) -> RegistrationResponse;
+
+        /// Request a job from the switchboard that can run on a device of one of the given classes.
+        /// Jobs that don't require a device class can be handed out regardless, but only jobs whose
+        /// spec the runner registered are, and none of the jobs the runner is still running.
+        async fn request_job(device_classes: Vec<String>, currently_running: Vec<uuid::Uuid>) -> JobResponse;
+
+        /// Tell the switchboard which device a job it handed out is running on.
+        async fn job_started(job_id: uuid::Uuid, device_id: uuid::Uuid);
+
+        /// Tell the switchboard that a device was quarantined for the given reason, or returned to
+        /// service if the reason is `None`.
+        async fn device_health_changed(device_id: uuid::Uuid, quarantine_reason: Option<String>);
 
         /// Notify the switchboard that the given job has stopped running, whether that's due to
         /// running to completion or to be canceled / having an error.
         async fn job_stopped(termination: JobTermination);
 
-        /// Request that the switchboard tell the client the IDs of any jobs currently assigned to
-        /// the client that were canceled, but have not yet stopped.
+        /// Append to the log of a running job. Chunks that don't continue exactly where the
+        /// switchboard's copy of the log ends are dropped.
+        async fn append_log(chunk: LogChunk);
+
+        /// Tell the switchboard that a running job reached a phase. A `Started` event also becomes
+        /// the job's start time.
+        async fn job_phase(event: PhaseEvent);
+
+        /// Request that the switchboard tell the client which of the jobs it is running it should
+        /// stop: all but those that are still started on this runner and weren't canceled.
         async fn request_cancellation_notifications(
             currently_running: Vec<uuid::Uuid>,
         ) -> Vec<uuid::Uuid>;
+
+        /// Wait until there is a job for the runner's idle devices of the given classes (or for
+        /// none, if it has no idle devices), or one of the jobs it is running should be canceled,
+        /// instead of polling `request_job` and `request_cancellation_notifications`. Returns an
+        /// empty notification after a while if neither happens, so that the call doesn't outlive
+        /// its deadline; the runner is then expected to wait again.
+        async fn wait_for_work(
+            idle_device_classes: Option<Vec<String>>,
+            currently_running: Vec<uuid::Uuid>,
+        ) -> WorkNotification;
+
+        /// Whether staff asked the runner to drain; see [`WorkNotification::drain_requested`],
+        /// which tells runners that wait for work instead of polling.
+        async fn drain_requested() -> bool;
     }
 }
 
 pub mod ctl {
+    use chrono::{DateTime, Utc};
     use serde::{Deserialize, Serialize};
     use thiserror::Error;
 
@@ -78,7 +270,10 @@ pub mod ctl {
 	#[error("{0}")]
 	InternalError(String),
 	#[error("not implemented")]
-	NotImplemented
+	NotImplemented,
+	/// The submission was well-formed but was refused, e.g. for exceeding a quota
+	#[error("submission rejected: {0}")]
+	InvalidSubmission(String),
     }
 
     #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -99,6 +294,17 @@ pub mod ctl {
         pub truncated: bool,
     }
 
+    /// The log of a job from some offset onwards.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct LogTail {
+        pub data: Vec<u8>,
+        /// Whether the job has stopped, i.e. whether the log is final
+        pub done: bool,
+        /// Which run of the job the log is of. It changes when the job is put back in the queue,
+        /// which starts its log over, so offsets into the log of another run don't apply.
+        pub attempt: u32,
+    }
+
     #[derive(Debug, Clone, Deserialize, Serialize)]
     pub enum JobResult {
         Pending,
@@ -110,6 +316,35 @@ pub mod ctl {
         Timeout,
     }
 
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct TestCase {
+        pub name: String,
+        pub passed: bool,
+        pub points: Option<f64>,
+        pub max_points: Option<f64>,
+        pub message: Option<String>,
+    }
+
+    /// When a job reached a phase of its latest run.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct PhaseTiming {
+        pub phase: crate::runner::JobPhase,
+        pub at: DateTime<Utc>,
+    }
+
+    /// Where a pending job stands in the queue.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct QueuePosition {
+        /// 1-based position among the pending jobs that compete for the same devices, in the order
+        /// the active scheduling policy will hand them out
+        pub position: u32,
+        /// Devices in service that can run the job, whether busy or idle
+        pub active_devices: u32,
+        /// When the job will probably start; `None` if there are no active devices or no recent
+        /// runs of its job spec to go by
+        pub estimated_start: Option<DateTime<Utc>>,
+    }
+
     #[derive(Debug, Clone, Deserialize, Serialize)]
     pub struct JobStatus {
 	pub job_spec: String,
@@ -117,19 +352,238 @@ pub mod ctl {
 	pub result: JobResult,
     }
 
+    /// The job that checked a user off on a lab: their earliest passing submission.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct Checkoff {
+        pub job_id: uuid::Uuid,
+        pub submitted: DateTime<Utc>,
+        /// Days past the user's deadline, rounded up; 0 if on time
+        pub days_late: u32,
+        /// Whether the user's late days covered `days_late`. Late days are spent on labs in
+        /// deadline order, and a lab that doesn't fit in what is left of the budget spends none.
+        pub excused: bool,
+    }
+    impl Checkoff {
+        pub fn on_time(&self) -> bool {
+            self.days_late == 0
+        }
+    }
+
+    /// A user's standing on one released lab.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct LabGrade {
+        pub lab: String,
+        pub job_spec: String,
+        /// The user's deadline: their extension if they have one, the lab's otherwise
+        pub deadline: Option<DateTime<Utc>>,
+        pub extended: bool,
+        /// Number of jobs the user submitted for the lab
+        pub attempts: u32,
+        /// Best result over all of the user's jobs for the lab, if they submitted any
+        pub best_result: Option<JobResult>,
+        pub checkoff: Option<Checkoff>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct LateDays {
+        pub budget: u32,
+        pub used: u32,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct Grades {
+        pub labs: Vec<LabGrade>,
+        pub late_days: LateDays,
+    }
+
     #[tarpc::service]
     pub trait Ctl {
 	async fn hi() -> String;
-	async fn submit(commit: String, job_spec: String) -> Result<(), CtlError>;
+	/// Submit a commit already in the user's repository, returning the new job's ID
+	async fn submit(commit: String, job_spec: String) -> Result<uuid::Uuid, CtlError>;
 	/// Return job history
 	///
 	/// If given a job spec, returns all jobs for that job spec. Otherwise,
 	/// returns most recent job for each job spec
 	async fn history(job_spec: Option<String>) -> Result<Vec<JobStatus>, CtlError>;
 	async fn status(job: JobReference) -> Result<JobStatus, CtlError>;
+	/// Return the log of a job; for a running job, the part streamed so far
 	async fn log(job: JobReference) -> Result<Log, CtlError>;
+	/// Return the per-test-case results of a job, in the order the test script reported them
+	async fn test_cases(job: JobReference) -> Result<Vec<TestCase>, CtlError>;
+	/// Return when the latest run of a job reached each phase, in the order it reached them
+	async fn phases(job: JobReference) -> Result<Vec<PhaseTiming>, CtlError>;
+	/// Return where a job stands in the queue, or `None` if it is not pending
+	async fn queue_position(job: JobReference) -> Result<Option<QueuePosition>, CtlError>;
+	/// Return the log of a job starting at byte `offset`, for following a running job
+	async fn tail_log(job: JobReference, offset: u64) -> Result<LogTail, CtlError>;
 	async fn cancel(job: JobReference) -> Result<JobStatus, CtlError>;
-    } 
+	/// Return the calling user's standing on every released lab, in release order
+	async fn grades() -> Result<Grades, CtlError>;
+    }
+}
+
+pub mod admin {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+
+    use crate::ctl::{CtlError, Grades, JobResult, JobStatus};
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct JobTypeConfig {
+        pub run_timeout_secs: u32,
+        pub cleanup_timeout_secs: u32,
+        pub max_log_bytes: u32,
+        pub device_class: Option<String>,
+        pub enabled: bool,
+    }
+    impl Default for JobTypeConfig {
+        fn default() -> Self {
+            Self {
+                run_timeout_secs: 120,
+                cleanup_timeout_secs: 45,
+                max_log_bytes: 64 * 1024,
+                device_class: None,
+                enabled: true,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct JobType {
+        pub spec: String,
+        pub config: JobTypeConfig,
+    }
+
+    /// A lab is checked off by passing its job type. It is hidden from students until it is
+    /// released.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct Lab {
+        pub name: String,
+        pub job_spec: String,
+        pub released: DateTime<Utc>,
+        pub deadline: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct StudentGrades {
+        pub user: String,
+        pub grades: Grades,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum LateStatus {
+        OnTime,
+        /// Late, but covered by the user's late days
+        Excused,
+        Late,
+        NotCheckedOff,
+    }
+
+    /// One user's standing on one lab, flattened for export.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct GradebookRow {
+        pub user: String,
+        pub lab: String,
+        pub job_spec: String,
+        pub best_result: Option<JobResult>,
+        pub first_pass: Option<DateTime<Utc>>,
+        pub attempts: u32,
+        pub days_late: u32,
+        pub late_status: LateStatus,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct DeviceStatus {
+        pub id: uuid::Uuid,
+        pub serial: String,
+        pub usb_bus: u8,
+        pub usb_ports: Vec<u8>,
+        pub class: Option<String>,
+        pub connected: bool,
+        pub current_job: Option<uuid::Uuid>,
+        pub quarantine_reason: Option<String>,
+        pub jobs_run: u32,
+        pub jobs_errored: u32,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct RunnerStatus {
+        pub id: uuid::Uuid,
+        pub name: String,
+        pub version: String,
+        pub job_specs: Vec<String>,
+        pub connected: bool,
+        pub peer_addr: String,
+        pub last_seen: DateTime<Utc>,
+        /// Staff asked the runner to drain, and it is still connected
+        pub draining: bool,
+        pub jobs_run: u32,
+        pub jobs_errored: u32,
+        pub devices: Vec<DeviceStatus>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct QueuedJob {
+        pub job_id: uuid::Uuid,
+        pub user: String,
+        pub job_spec: String,
+        pub result: JobResult,
+        pub submitted: DateTime<Utc>,
+        pub started: Option<DateTime<Utc>>,
+    }
+
+    /// Staff-only operations. Every call checks that the peer is a member of the switchboard's
+    /// configured admin group, and returns [`CtlError::PermissionDenied`] otherwise.
+    #[tarpc::service]
+    pub trait Admin {
+        /// Add a user to the database and start listening on their submission socket, returning
+        /// their ID.
+        async fn add_user(name: String) -> Result<uuid::Uuid, CtlError>;
+        /// Remove a user from the database and close their submission socket. Fails if the user
+        /// still owns jobs, unless `purge_jobs` is set, in which case their jobs are deleted too.
+        async fn remove_user(name: String, purge_jobs: bool) -> Result<(), CtlError>;
+        /// Bring the submission socket listeners in line with the `users` table, e.g. after users
+        /// were added with raw SQL. Returns the number of listeners spawned.
+        async fn sync_listeners() -> Result<u32, CtlError>;
+        /// Create a new job type, returning its ID.
+        async fn create_job_type(spec: String, config: JobTypeConfig) -> Result<uuid::Uuid, CtlError>;
+        /// Replace the configuration of an existing job type. Jobs that were already handed to a
+        /// runner keep the configuration they started with.
+        async fn configure_job_type(spec: String, config: JobTypeConfig) -> Result<(), CtlError>;
+        /// List all job types and their configuration.
+        async fn job_types() -> Result<Vec<JobType>, CtlError>;
+        /// Create a new lab for an existing job type, returning its ID.
+        async fn create_lab(lab: Lab) -> Result<uuid::Uuid, CtlError>;
+        /// Replace the job type, release date and deadline of the lab with the same name.
+        async fn configure_lab(lab: Lab) -> Result<(), CtlError>;
+        /// List all labs, released or not, in release order.
+        async fn labs() -> Result<Vec<Lab>, CtlError>;
+        /// Set how many late days a user may spend, or reset them to the switchboard's default.
+        async fn set_late_day_budget(user: String, days: Option<u32>) -> Result<(), CtlError>;
+        /// Give a user their own deadline for a lab, replacing any previous extension.
+        async fn grant_extension(user: String, lab: String, deadline: DateTime<Utc>) -> Result<(), CtlError>;
+        /// Put a user back on a lab's regular deadline.
+        async fn revoke_extension(user: String, lab: String) -> Result<(), CtlError>;
+        /// Every user's standing on every released lab, including their late days. If `users` is
+        /// non-empty, only those users are included.
+        async fn grades(users: Vec<String>) -> Result<Vec<StudentGrades>, CtlError>;
+        /// One row per user and released lab, ordered by user and then lab release. Empty `users`
+        /// or `labs` means all of them.
+        async fn gradebook(users: Vec<String>, labs: Vec<String>) -> Result<Vec<GradebookRow>, CtlError>;
+        /// List every runner that has ever registered, and its devices.
+        async fn runners() -> Result<Vec<RunnerStatus>, CtlError>;
+        /// List all running jobs, followed by all queued jobs in the order they will be run in.
+        async fn queue() -> Result<Vec<QueuedJob>, CtlError>;
+        /// Cancel any user's job with the given reason.
+        async fn force_cancel(job_id: uuid::Uuid, reason: String) -> Result<JobStatus, CtlError>;
+        /// Put a job that is not running back into the queue, discarding any previous run.
+        async fn requeue(job_id: uuid::Uuid) -> Result<JobStatus, CtlError>;
+        /// Have a connected runner finish the jobs it is running without taking new ones, and then
+        /// exit, e.g. to take its machine down for maintenance.
+        async fn drain_runner(name: String) -> Result<(), CtlError>;
+    }
 }
 
 pub mod submit {
@@ -140,3 +594,19 @@ pub mod submit {
         pub spec: String,
     }
 }
+
+/// Mutual TLS between the switchboard and runners.
+pub mod tls {
+    use std::path::Path;
+
+    use rustls_pki_types::{CertificateDer, pem::PemObject as _};
+
+    /// Reads the PEM certificates at `path`, of which there must be at least one.
+    pub fn load_certs(path: &Path) -> eyre::Result<Vec<CertificateDer<'static>>> {
+        let certs: Vec<_> = CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<Result<_, _>>())
+            .map_err(|e| eyre::eyre!("failed to read certificates from {}: {e}", path.display()))?;
+        eyre::ensure!(!certs.is_empty(), "no certificates in {}", path.display());
+        Ok(certs)
+    }
+}
@@ -1,46 +1,208 @@
 use std::{
     cell::Cell,
-    path::PathBuf,
+    collections::{HashMap, VecDeque},
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::Arc,
     task::{Poll, Waker},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytes::{Buf as _, BufMut as _, BytesMut};
-use futures::{SinkExt, Stream, StreamExt, stream::FuturesUnordered};
+use futures::{FutureExt as _, SinkExt, Stream, StreamExt, future::BoxFuture, stream::FuturesUnordered};
 use gradecope_proto::runner::{
-    JobResponse, SwitchboardClient, SwitchboardRequest, SwitchboardResponse,
+    DeviceInfo, JobResponse, JobResult, JobSpec, JobTermination, RegistrationResponse,
+    RunnerRegistration, SwitchboardClient, SwitchboardRequest, SwitchboardResponse,
+    WorkNotification,
 };
-use tarpc::{ClientMessage, Response, transport::channel::Channel};
-use tokio::net::TcpStream;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::Message};
+use tarpc::{ClientMessage, Response, client::RpcError, transport::channel::Channel};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
 use uuid::Uuid;
 
-use crate::DeviceCtl;
+use crate::{DeviceCtl, DispatchMode, runner::WorkerMsg};
 
-pub async fn connect(opts: crate::Opts, devices: Vec<(Uuid, DeviceCtl)>) -> eyre::Result<()> {
-    let req = format!("ws://{}/runner/control", opts.remote);
-    let (stream, _response) = tokio_tungstenite::connect_async_with_config(&req, None, false)
-        .await
-        .inspect_err(|e| {
-            tracing::error!("Failed to connect to `{req}`: {e:?}");
-        })?;
+/// Deadline of `wait_for_work` calls, which the switchboard answers within 20s even if there is no
+/// work.
+const WORK_WAIT_DEADLINE: Duration = Duration::from_secs(60);
 
-    tracing::info!("Connect to remote at {}", opts.remote);
-    let (client_channel, server_channel) = tarpc::transport::channel::bounded(16);
-    let client = SwitchboardClient::new(tarpc::client::Config::default(), client_channel).spawn();
+/// A running job, its device, and what cancels it, with a reason if the runner is canceling it of
+/// its own accord.
+type Assignment = (JobSpec, Uuid, DeviceCtl, Option<oneshot::Sender<Option<String>>>);
+
+/// Backoff between attempts to reconnect to the switchboard, which doubles up to the maximum with
+/// each attempt, whether registering fails or the connection is lost soon after.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// How long a connection has to stay up for the backoff to start over once it is lost.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// Registers with the switchboard and runs the jobs it hands out on `devices`, reconnecting
+/// whenever the connection is lost. Jobs keep running while the runner is disconnected, and are
+/// reported on once it has reconnected.
+///
+/// Returns once the runner has drained, which it starts doing on SIGTERM or SIGINT, or when staff
+/// ask the switchboard to drain it: it takes no more jobs, and returns once it has reported on the
+/// ones it was running. A second signal cancels the running jobs, and a third returns right away.
+pub async fn connect(
+    opts: crate::Opts,
+    name: String,
+    devices: Vec<(Uuid, DeviceCtl)>,
+) -> eyre::Result<()> {
+    let remote = Remote {
+        addr: opts.remote.clone(),
+        tls: match (&opts.tls_ca, &opts.tls_cert, &opts.tls_key) {
+            (Some(ca), Some(cert), Some(key)) => Some(crate::tls::client_config(ca, cert, key)?),
+            _ => None,
+        },
+    };
+    let registration = RunnerRegistration {
+        name,
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        devices: devices
+            .iter()
+            .map(|(id, device)| DeviceInfo {
+                id: *id,
+                serial: device.serial.display().to_string(),
+                usb_bus: device.usb_dev.bus_number(),
+                usb_ports: device.usb_dev.port_numbers().unwrap_or_default(),
+                class: device.class.clone(),
+            })
+            .collect(),
+        job_specs: available_job_specs(&opts.test_runner)?,
+        unfinished_jobs: vec![],
+    };
+    let signals = Signals::new()?;
 
-    tokio::spawn(server_proxy(stream, server_channel));
     dispatcher(
-        client,
+        remote,
+        signals,
+        registration,
         devices,
-        opts.test_runner,
+        opts.test_runner.clone(),
         Duration::from_millis(opts.poll_interval_ms),
+        opts.dispatch,
+        opts.quarantine_after,
+        HealthCheckConfig {
+            script: opts.healthcheck.clone().or_else(|| {
+                let default = opts.test_runner.join("healthcheck.sh");
+                default.exists().then_some(default)
+            }),
+            interval: Duration::from_secs(opts.healthcheck_interval_secs),
+            timeout: Duration::from_secs(opts.healthcheck_timeout_secs),
+        },
     )
     .await;
 
     Ok(())
 }
 
+/// The signals that make the runner drain.
+struct Signals {
+    sigterm: tokio::signal::unix::Signal,
+    sigint: tokio::signal::unix::Signal,
+}
+impl Signals {
+    fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+        Ok(Self {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.sigterm.recv() => (),
+            _ = self.sigint.recv() => (),
+        }
+    }
+}
+
+/// A connection to the switchboard that the runner has registered on.
+struct Connection {
+    client: SwitchboardClient,
+    /// Finishes once the websocket is closed.
+    proxy: JoinHandle<()>,
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.proxy.abort();
+    }
+}
+
+/// Where the switchboard is, and how to authenticate with it.
+#[derive(Clone)]
+struct Remote {
+    addr: String,
+    /// Connect over mutual TLS with this configuration, instead of plain HTTP
+    tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+async fn open_connection(remote: &Remote, registration: RunnerRegistration) -> eyre::Result<Connection> {
+    let scheme = if remote.tls.is_some() { "wss" } else { "ws" };
+    let req = format!("{scheme}://{}/runner/control", remote.addr);
+    let stream = TcpStream::connect(&remote.addr)
+        .await
+        .map_err(|e| eyre::eyre!("failed to connect to `{req}`: {e}"))?;
+    let (client_channel, server_channel) = tarpc::transport::channel::bounded(16);
+    let proxy = match &remote.tls {
+        Some(tls) => {
+            let stream = crate::tls::connect(tls.clone(), &remote.addr, stream).await?;
+            let (ws, _response) = tokio_tungstenite::client_async(&req, stream)
+                .await
+                .map_err(|e| eyre::eyre!("failed to connect to `{req}`: {e}"))?;
+            tokio::spawn(server_proxy(ws, server_channel))
+        }
+        None => {
+            let (ws, _response) = tokio_tungstenite::client_async(&req, stream)
+                .await
+                .map_err(|e| eyre::eyre!("failed to connect to `{req}`: {e}"))?;
+            tokio::spawn(server_proxy(ws, server_channel))
+        }
+    };
+
+    tracing::info!("Connect to remote at {}", remote.addr);
+    let connection = Connection {
+        client: SwitchboardClient::new(tarpc::client::Config::default(), client_channel).spawn(),
+        proxy,
+    };
+
+    tracing::info!(
+        "Registering as {} with job specs {:?} and unfinished jobs {:?}",
+        registration.name,
+        registration.job_specs,
+        registration.unfinished_jobs
+    );
+    match connection.client.register(tarpc::context::current(), registration).await? {
+        RegistrationResponse::Accepted => Ok(connection),
+        RegistrationResponse::Rejected(reason) => {
+            eyre::bail!("switchboard rejected registration: {reason}")
+        }
+    }
+}
+
+/// The job specs this runner can run: those with a `.run.sh` script in `test_runner`.
+fn available_job_specs(test_runner: &Path) -> eyre::Result<Vec<String>> {
+    let mut job_specs = vec![];
+    for entry in std::fs::read_dir(test_runner)? {
+        let file_name = entry?.file_name();
+        if let Some(spec) = file_name.to_str().and_then(|s| s.strip_suffix(".run.sh")) {
+            job_specs.push(spec.to_owned());
+        }
+    }
+    job_specs.sort();
+    if job_specs.is_empty() {
+        tracing::warn!("No *.run.sh scripts in {}; no jobs will be run", test_runner.display());
+    }
+    Ok(job_specs)
+}
+
 type ServerChannel = Channel<ClientMessage<SwitchboardRequest>, Response<SwitchboardResponse>>;
 
 #[pin_project::pin_project]
@@ -80,26 +242,265 @@ impl<T: Future> Stream for IncompleteFutures<T> {
     }
 }
 
+/// How devices are health-checked; see the corresponding `Opts`.
+struct HealthCheckConfig {
+    script: Option<PathBuf>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+/// A device that was health-checked, whether it was quarantined before the check and why, and the
+/// result of the check.
+type HealthCheckOutcome = (Uuid, DeviceCtl, Option<String>, Result<(), String>);
+
+fn spawn_health_check(
+    worker_id: Uuid,
+    device: DeviceCtl,
+    quarantine_reason: Option<String>,
+    config: &HealthCheckConfig,
+) -> tokio::task::JoinHandle<HealthCheckOutcome> {
+    let script = config.script.clone();
+    let timeout = config.timeout;
+    tokio::spawn(async move {
+        let check = crate::runner::check_health(worker_id, device.clone(), script, timeout);
+        // a check that panics couldn't vouch for the device, but mustn't lose it either
+        let health = AssertUnwindSafe(check)
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|_| Err("health check panicked".to_owned()));
+        (worker_id, device, quarantine_reason, health)
+    })
+}
+
+async fn report_device_health(
+    client: &SwitchboardClient,
+    worker_id: Uuid,
+    quarantine_reason: Option<String>,
+) {
+    match &quarantine_reason {
+        Some(reason) => tracing::warn!("Quarantining device {worker_id}: {reason}"),
+        None => tracing::info!("Device {worker_id} passed its health check, returning it to service"),
+    }
+    if let Err(e) = client
+        .device_health_changed(tarpc::context::current(), worker_id, quarantine_reason)
+        .await
+    {
+        tracing::error!("RPC error sending device health: {e:?}");
+    }
+}
+
+/// Updates a device's count of consecutive errored jobs with the job that just finished on it, and
+/// decides whether the device should be quarantined. If so, returns the reason, and turns the job's
+/// result into a `DeviceFailure` so that the switchboard requeues it.
+fn check_device_health(
+    consecutive_errors: &mut u32,
+    termination: &mut JobTermination,
+    quarantine_after: u32,
+) -> Option<String> {
+    match termination.result {
+        JobResult::DeviceFailure => Some(
+            termination
+                .device_failure
+                .clone()
+                .unwrap_or_else(|| "device failed while running a job".to_owned()),
+        ),
+        JobResult::Error => {
+            *consecutive_errors += 1;
+            if *consecutive_errors >= quarantine_after {
+                let reason = format!("{consecutive_errors} consecutive jobs errored");
+                termination.result = JobResult::DeviceFailure;
+                termination.device_failure = Some(reason.clone());
+                Some(reason)
+            } else {
+                None
+            }
+        }
+        _ => {
+            *consecutive_errors = 0;
+            None
+        }
+    }
+}
+
+/// In push mode, a wait for work is kept outstanding, for the idle devices and running jobs it was
+/// made for; once those change, it is replaced (which cancels it).
+type WorkWait = (
+    (Option<Vec<String>>, Vec<Uuid>),
+    BoxFuture<'static, Result<WorkNotification, RpcError>>,
+);
+
+/// Drops the connection after an RPC on it failed, so that the dispatcher reconnects.
+fn lose_connection(
+    connection: &mut Option<Connection>,
+    work_wait: &mut Option<WorkWait>,
+    what: &str,
+    e: RpcError,
+) {
+    tracing::error!("RPC error {what}: {e:?}, reconnecting");
+    *connection = None;
+    *work_wait = None;
+}
+
+/// Reports the terminations of finished jobs, oldest first. A termination that can't be sent is
+/// kept, along with those after it, to be sent once reconnected.
+async fn report_terminations(
+    client: &SwitchboardClient,
+    pending_terminations: &mut VecDeque<JobTermination>,
+) -> Result<(), RpcError> {
+    while let Some(termination) = pending_terminations.front() {
+        client.job_stopped(tarpc::context::current(), termination.clone()).await?;
+        pending_terminations.pop_front();
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn dispatcher(
-    client: SwitchboardClient,
-    mut devices: Vec<(Uuid, DeviceCtl)>,
+    remote: Remote,
+    mut signals: Signals,
+    registration: RunnerRegistration,
+    devices: Vec<(Uuid, DeviceCtl)>,
     test_runner: PathBuf,
     poll_interval: Duration,
+    dispatch: DispatchMode,
+    quarantine_after: u32,
+    health_check: HealthCheckConfig,
 ) {
-    let mut assignments = vec![];
-    let mut termination_receivers = IncompleteFutures::new();
+    let mut connection: Option<Connection> = None;
+    let mut connecting: Option<BoxFuture<'static, eyre::Result<Connection>>> = None;
+    let mut reconnect_delay = Duration::ZERO;
+    let mut connected_at: Option<Instant> = None;
+    // Set once the switchboard says it is shutting down, until reconnecting to its next instance
+    let mut switchboard_draining = false;
+    // Set once the runner itself is draining, after which it takes no more jobs
+    let mut draining = false;
+    let mut signals_received = 0;
+    // Terminations of finished jobs, held until they have been reported
+    let mut pending_terminations: VecDeque<JobTermination> = VecDeque::new();
+
+    let mut assignments: Vec<Assignment> = vec![];
+    let mut consecutive_errors: HashMap<Uuid, u32> = HashMap::new();
+    // Devices that have to pass a health check before running jobs, along with why they were
+    // quarantined, if they were. Every device starts out here, unchecked.
+    let mut out_of_service: Vec<(Uuid, DeviceCtl, Option<String>)> =
+        devices.into_iter().map(|(id, device)| (id, device, None)).collect();
+    let mut devices: Vec<(Uuid, DeviceCtl)> = vec![];
+    let mut last_checked: HashMap<Uuid, Instant> = HashMap::new();
+    let mut health_checks: IncompleteFutures<tokio::task::JoinHandle<HealthCheckOutcome>> =
+        IncompleteFutures::new();
+    let mut termination_receivers: IncompleteFutures<oneshot::Receiver<JobTermination>> =
+        IncompleteFutures::new();
+
+    let (worker_tx, mut worker_rx) = mpsc::channel(64);
 
     let mut poll_interval = tokio::time::interval(poll_interval);
 
+    let mut work_wait: Option<WorkWait> = None;
+
     'outer: loop {
+        if connection.is_none() && connecting.is_none() {
+            // No jobs are taken while disconnected, so these are all the jobs the switchboard
+            // may still think the runner has.
+            let registration = RunnerRegistration {
+                unfinished_jobs: assignments
+                    .iter()
+                    .map(|a| a.0.id)
+                    .chain(pending_terminations.iter().map(|t| t.job_id))
+                    .collect(),
+                ..registration.clone()
+            };
+            let remote = remote.clone();
+            if connected_at.take().is_some_and(|at| at.elapsed() >= STABLE_CONNECTION) {
+                reconnect_delay = Duration::ZERO;
+            }
+            let delay = reconnect_delay;
+            reconnect_delay = (reconnect_delay * 2).clamp(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+            connecting = Some(Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                open_connection(&remote, registration).await
+            }));
+        }
+
+        if let Some(conn) = &connection
+            && let Err(e) = report_terminations(&conn.client, &mut pending_terminations).await
+        {
+            lose_connection(&mut connection, &mut work_wait, "sending job termination status", e);
+        }
+
+        if draining && assignments.is_empty() && pending_terminations.is_empty() {
+            tracing::info!("Drained; exiting");
+            return;
+        }
+
+        if dispatch == DispatchMode::Push
+            && let Some(conn) = &connection
+        {
+            let waiting_for = (
+                (!devices.is_empty() && !switchboard_draining && !draining)
+                    .then(|| idle_device_classes(&devices)),
+                assignments.iter().map(|a| a.0.id).collect::<Vec<_>>(),
+            );
+            if work_wait.as_ref().is_none_or(|(w, _)| *w != waiting_for) {
+                let mut ctx = tarpc::context::current();
+                ctx.deadline = Instant::now() + WORK_WAIT_DEADLINE;
+                let client = conn.client.clone();
+                let (idle_device_classes, currently_running) = waiting_for.clone();
+                work_wait = Some((
+                    waiting_for,
+                    Box::pin(async move {
+                        client.wait_for_work(ctx, idle_device_classes, currently_running).await
+                    }),
+                ));
+            }
+        }
+
         tokio::select! {
         biased;
+        _ = signals.recv() => {
+            signals_received += 1;
+            match signals_received {
+                1 => {
+                    tracing::info!(
+                        "Draining: waiting for {} running job(s); signal again to cancel them",
+                        assignments.len()
+                    );
+                    draining = true;
+                }
+                2 => {
+                    tracing::warn!("Canceling {} running job(s); signal again to exit right away", assignments.len());
+                    let job_ids = assignments.iter().map(|a| a.0.id).collect();
+                    cancel_jobs(&mut assignments, job_ids, Some("runner was shut down"));
+                }
+                _ => {
+                    tracing::warn!(
+                        "Exiting without reporting on {} job(s)",
+                        assignments.len() + pending_terminations.len()
+                    );
+                    return;
+                }
+            }
+        }
         msg = termination_receivers.next() => {
             match msg {
-                Some(Ok(termination)) => {
-                    if let Err(e) = client.job_stopped(tarpc::context::current(), termination).await {
-                        tracing::error!("RPC error sending job termination status: {e:?}");
+                Some(Ok(mut termination)) => {
+                    // The device is only free once the job has fully stopped, including cleanup.
+                    if let Some(pos) = assignments.iter().position(|a| a.0.id == termination.job_id) {
+                        let (_job_spec, worker_id, device, _cancel_tx) = assignments.remove(pos);
+                        let errors = consecutive_errors.entry(worker_id).or_default();
+                        match check_device_health(errors, &mut termination, quarantine_after) {
+                            None => devices.push((worker_id, device)),
+                            Some(reason) => {
+                                consecutive_errors.remove(&worker_id);
+                                if let Some(conn) = &connection {
+                                    report_device_health(&conn.client, worker_id, Some(reason.clone())).await;
+                                }
+                                // check the device again right away; it may just need a power cycle
+                                last_checked.remove(&worker_id);
+                                out_of_service.push((worker_id, device, Some(reason)));
+                            }
+                        }
                     }
+                    pending_terminations.push_back(termination);
                 }
                 Some(Err(e)) => {
                     // wtf
@@ -113,58 +514,176 @@ async fn dispatcher(
                 }
             }
         }
+        res = async { connecting.as_mut().unwrap().await }, if connecting.is_some() => {
+            connecting = None;
+            match res {
+                Ok(conn) => {
+                    connected_at = Some(Instant::now());
+                    switchboard_draining = false;
+                    // registering returns every device to service, so quarantines are reported again
+                    for (worker_id, _, quarantine_reason) in &out_of_service {
+                        if quarantine_reason.is_some() {
+                            report_device_health(&conn.client, *worker_id, quarantine_reason.clone()).await;
+                        }
+                    }
+                    connection = Some(conn);
+                }
+                Err(e) => {
+                    tracing::error!("Failed to register with switchboard: {e:?}; retrying in {reconnect_delay:?}");
+                }
+            }
+        }
+        _ = async { (&mut connection.as_mut().unwrap().proxy).await }, if connection.is_some() => {
+            tracing::warn!("Lost connection to switchboard, reconnecting");
+            connection = None;
+            work_wait = None;
+        }
+        Some(outcome) = health_checks.next() => {
+            let (worker_id, device, quarantine_reason, health) = match outcome {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    tracing::error!("Health check task failed, losing its device: {e:?}");
+                    continue 'outer;
+                }
+            };
+            last_checked.insert(worker_id, Instant::now());
+            match health {
+                Ok(()) => {
+                    if quarantine_reason.is_some()
+                        && let Some(conn) = &connection
+                    {
+                        report_device_health(&conn.client, worker_id, None).await;
+                    }
+                    devices.push((worker_id, device));
+                }
+                Err(reason) => {
+                    if quarantine_reason.as_ref() != Some(&reason)
+                        && let Some(conn) = &connection
+                    {
+                        report_device_health(&conn.client, worker_id, Some(reason.clone())).await;
+                    }
+                    out_of_service.push((worker_id, device, Some(reason)));
+                }
+            }
+        }
+        res = async { work_wait.as_mut().unwrap().1.as_mut().await }, if work_wait.is_some() => {
+            work_wait = None;
+            let notification = match res {
+                Ok(notification) => notification,
+                Err(e) => {
+                    lose_connection(&mut connection, &mut work_wait, "waiting for work", e);
+                    continue 'outer;
+                }
+            };
+            if notification.draining && !switchboard_draining {
+                tracing::info!("Switchboard is shutting down; taking no more jobs until it is back");
+                switchboard_draining = true;
+            }
+            if notification.drain_requested && !draining {
+                tracing::info!("Switchboard asked the runner to drain; waiting for {} running job(s)", assignments.len());
+                draining = true;
+            }
+            cancel_jobs(&mut assignments, notification.cancellations, None);
+            if notification.jobs_available
+                && !draining
+                && let Some(conn) = &connection
+                && let Err(e) = request_jobs(
+                    &conn.client,
+                    &mut devices,
+                    &mut assignments,
+                    &termination_receivers,
+                    &test_runner,
+                    &worker_tx,
+                )
+                .await
+            {
+                lose_connection(&mut connection, &mut work_wait, "requesting job", e);
+            }
+        }
+        Some(msg) = worker_rx.recv() => {
+            // These are dropped while disconnected, so that jobs don't block on sending them; the
+            // full log and every phase are sent with the termination anyway.
+            let Some(conn) = &connection else {
+                continue 'outer;
+            };
+            match msg {
+                WorkerMsg::LogChunk(chunk) => {
+                    if let Err(e) = conn.client.append_log(tarpc::context::current(), chunk).await {
+                        tracing::error!("RPC error sending log chunk: {e:?}");
+                    }
+                }
+                WorkerMsg::Phase(event) => {
+                    if let Err(e) = conn.client.job_phase(tarpc::context::current(), event).await {
+                        tracing::error!("RPC error sending job phase: {e:?}");
+                    }
+                }
+            }
+        }
         _ = poll_interval.tick() => {
-            'assignments: while !devices.is_empty() {
-                // tracing::debug!("Submitting job request");
-                let job_spec = match client.request_job(tarpc::context::current()).await {
-                    Ok(JobResponse::Job(job_spec)) => job_spec,
-                    Ok(JobResponse::Unavailable) => break 'assignments,
+            // Idle devices are checked between jobs, and out-of-service devices until they pass;
+            // a draining runner won't use them again
+            let now = Instant::now();
+            let due = |id: &Uuid| {
+                !draining
+                    && last_checked
+                        .get(id)
+                        .is_none_or(|t| now.duration_since(*t) >= health_check.interval)
+            };
+            for (worker_id, device, quarantine_reason) in
+                out_of_service.extract_if(.., |(id, _, _)| due(id))
+            {
+                health_checks.push(spawn_health_check(worker_id, device, quarantine_reason, &health_check));
+            }
+            for (worker_id, device) in devices.extract_if(.., |(id, _)| due(id)) {
+                health_checks.push(spawn_health_check(worker_id, device, None, &health_check));
+            }
+
+            // in push mode, jobs and cancellations come from `work_wait` instead
+            if dispatch == DispatchMode::Push {
+                continue 'outer;
+            }
+            let Some(conn) = &connection else {
+                continue 'outer;
+            };
+            if !draining {
+                match conn.client.drain_requested(tarpc::context::current()).await {
+                    Ok(true) => {
+                        tracing::info!("Switchboard asked the runner to drain; waiting for {} running job(s)", assignments.len());
+                        draining = true;
+                    }
+                    Ok(false) => (),
                     Err(e) => {
-                        tracing::error!("RPC error requesting job: {e:?}, killing dispatcher");
-                        break 'outer;
+                        lose_connection(&mut connection, &mut work_wait, "checking for drain request", e);
+                        continue 'outer;
                     }
-                };
-                let (worker_id, device) = devices.pop().unwrap();
-                let (cancel_tx, cancel_rx) = tokio::sync::oneshot::channel();
-                let (return_tx, return_rx) = tokio::sync::oneshot::channel();
-                let _handle = tokio::spawn(crate::runner::run_job(
-                    worker_id,
-                    device.clone(),
-                    test_runner.clone(),
-                    job_spec.clone(),
-                    cancel_rx,
-                    return_tx,
-                ));
-                termination_receivers.push(return_rx);
-                assignments.push((job_spec, worker_id, device, cancel_tx));
+                }
+            }
+            if !draining
+                && let Err(e) = request_jobs(
+                    &conn.client,
+                    &mut devices,
+                    &mut assignments,
+                    &termination_receivers,
+                    &test_runner,
+                    &worker_tx,
+                )
+                .await
+            {
+                lose_connection(&mut connection, &mut work_wait, "requesting job", e);
+                continue 'outer;
             }
 
-            match client
+            match conn
+                .client
                 .request_cancellation_notifications(
                     tarpc::context::current(),
                     assignments.iter().map(|assn| assn.0.id).collect(),
                 )
                 .await
             {
-                Ok(job_ids) => {
-                    'cancellations: for job_id in job_ids {
-                        let Some(pos) = assignments.iter().position(|a| a.0.id == job_id) else {
-                            continue 'cancellations;
-                        };
-                        let (_job_spec, worker_id, device, cancel_tx) = assignments.remove(pos);
-
-                        if let Err(_) = cancel_tx.send(()) {
-                            // receiver has been deallocated, no-op
-                        }
-
-                        devices.push((worker_id, device));
-                    }
-                }
+                Ok(job_ids) => cancel_jobs(&mut assignments, job_ids, None),
                 Err(e) => {
-                    tracing::error!(
-                        "RPC error requesting cancellation notifications: {e:?}, killing dispatcher"
-                    );
-                    break 'outer;
+                    lose_connection(&mut connection, &mut work_wait, "requesting cancellation notifications", e);
                 }
             };
         }
@@ -172,8 +691,102 @@ async fn dispatcher(
     }
 }
 
-async fn server_proxy(
-    mut ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+/// Requests jobs for idle devices until the switchboard has none left for them, and starts them.
+async fn request_jobs(
+    client: &SwitchboardClient,
+    devices: &mut Vec<(Uuid, DeviceCtl)>,
+    assignments: &mut Vec<Assignment>,
+    termination_receivers: &IncompleteFutures<oneshot::Receiver<JobTermination>>,
+    test_runner: &Path,
+    worker_tx: &mpsc::Sender<WorkerMsg>,
+) -> Result<(), RpcError> {
+    while !devices.is_empty() {
+        let device_classes = idle_device_classes(devices);
+        let currently_running = assignments.iter().map(|assn| assn.0.id).collect();
+        let job_spec = match client
+            .request_job(tarpc::context::current(), device_classes, currently_running)
+            .await?
+        {
+            JobResponse::Job(job_spec) => job_spec,
+            JobResponse::Unavailable => break,
+        };
+        let Some(device_idx) = pick_device(devices, job_spec.device_class.as_deref()) else {
+            // only classes of idle devices are requested, so this shouldn't happen; it's not the
+            // student's fault, so the job goes back in the queue
+            tracing::error!(
+                "Switchboard sent job {} for device class {:?}, which has no idle device",
+                job_spec.id, job_spec.device_class
+            );
+            let reason = format!("no idle device of class {:?}", job_spec.device_class);
+            let termination = JobTermination::device_failed(job_spec.id, None, reason);
+            if let Err(e) = client.job_stopped(tarpc::context::current(), termination).await {
+                tracing::error!("RPC error sending job termination status: {e:?}");
+            }
+            break;
+        };
+        let (worker_id, device) = devices.swap_remove(device_idx);
+        if let Err(e) = client.job_started(tarpc::context::current(), job_spec.id, worker_id).await {
+            tracing::error!("RPC error sending job start: {e:?}");
+        }
+        let (cancel_tx, cancel_rx) = tokio::sync::oneshot::channel();
+        let (return_tx, return_rx) = tokio::sync::oneshot::channel();
+        let _handle = tokio::spawn(crate::runner::run_job(
+            worker_id,
+            device.clone(),
+            test_runner.to_owned(),
+            job_spec.clone(),
+            cancel_rx,
+            worker_tx.clone(),
+            return_tx,
+        ));
+        termination_receivers.push(return_rx);
+        assignments.push((job_spec, worker_id, device, Some(cancel_tx)));
+    }
+    Ok(())
+}
+
+/// Tells the workers running the given jobs to cancel them, giving `reason` if the runner cancels
+/// them of its own accord.
+fn cancel_jobs(assignments: &mut [Assignment], job_ids: Vec<Uuid>, reason: Option<&str>) {
+    for job_id in job_ids {
+        let Some(assignment) = assignments.iter_mut().find(|a| a.0.id == job_id) else {
+            continue;
+        };
+
+        // The worker reports back through its termination channel once it has
+        // killed the job and collected the log; the device is released then.
+        if let Some(cancel_tx) = assignment.3.take() {
+            // receiver may have been deallocated, no-op
+            let _ = cancel_tx.send(reason.map(str::to_owned));
+        }
+    }
+}
+
+/// The classes of the given idle devices, to ask the switchboard for jobs that can run on them.
+fn idle_device_classes(devices: &[(Uuid, DeviceCtl)]) -> Vec<String> {
+    let mut device_classes: Vec<String> =
+        devices.iter().filter_map(|(_, d)| d.class.clone()).collect();
+    device_classes.sort();
+    device_classes.dedup();
+    device_classes
+}
+
+/// Picks an idle device for a job requiring `class`. Jobs without a class requirement go to
+/// unclassed devices first, so that classed devices stay free for jobs that need them.
+fn pick_device(devices: &[(Uuid, DeviceCtl)], class: Option<&str>) -> Option<usize> {
+    match class {
+        Some(class) => devices
+            .iter()
+            .position(|(_, d)| d.class.as_deref() == Some(class)),
+        None => devices
+            .iter()
+            .position(|(_, d)| d.class.is_none())
+            .or((!devices.is_empty()).then_some(0)),
+    }
+}
+
+async fn server_proxy<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ws: WebSocketStream<S>,
     mut server_channel: ServerChannel,
 ) {
     let mut ping_interval = tokio::time::interval(Duration::from_secs(2));
@@ -287,3 +900,71 @@ async fn server_proxy(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn termination(result: JobResult) -> JobTermination {
+        JobTermination::failed(Uuid::new_v4(), Some(Uuid::new_v4()), result)
+    }
+
+    /// Runs jobs with the given results through the health check in order, returning the
+    /// quarantine decision for each and the results as they would be reported.
+    fn run(results: &[JobResult], quarantine_after: u32) -> Vec<(Option<String>, JobResult)> {
+        let mut consecutive_errors = 0;
+        results
+            .iter()
+            .map(|result| {
+                let mut termination = termination(result.clone());
+                let reason =
+                    check_device_health(&mut consecutive_errors, &mut termination, quarantine_after);
+                (reason, termination.result)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn error_streak_quarantines_and_requeues() {
+        let checked = run(&[JobResult::Error, JobResult::Error, JobResult::Error], 3);
+        assert!(
+            checked[..2]
+                .iter()
+                .all(|(reason, result)| reason.is_none() && matches!(result, JobResult::Error))
+        );
+        let (reason, result) = &checked[2];
+        assert_eq!(reason.as_deref(), Some("3 consecutive jobs errored"));
+        assert!(matches!(result, JobResult::DeviceFailure));
+    }
+
+    #[test]
+    fn other_results_reset_the_streak() {
+        let checked = run(
+            &[
+                JobResult::Error,
+                JobResult::Error,
+                JobResult::Incorrect,
+                JobResult::Error,
+                JobResult::Error,
+                JobResult::Timeout,
+                JobResult::Error,
+            ],
+            3,
+        );
+        assert!(checked.iter().all(|(reason, _)| reason.is_none()));
+        assert!(!checked.iter().any(|(_, result)| matches!(result, JobResult::DeviceFailure)));
+    }
+
+    #[test]
+    fn device_failures_quarantine_right_away() {
+        let mut consecutive_errors = 0;
+        let mut termination = JobTermination::device_failed(
+            Uuid::new_v4(),
+            Some(Uuid::new_v4()),
+            "lab1.cleanup.sh timed out".to_owned(),
+        );
+        let reason = check_device_health(&mut consecutive_errors, &mut termination, 3);
+        assert_eq!(reason.as_deref(), Some("lab1.cleanup.sh timed out"));
+        assert!(matches!(termination.result, JobResult::DeviceFailure));
+    }
+}
@@ -0,0 +1,308 @@
+//! Capturing a device's serial console output while it runs a job.
+//!
+//! Only the runner reads the device's serial port while its console is captured, so that nothing
+//! the device writes is split between two readers. The job's scripts get a pty in its place
+//! instead, which the runner passes everything the device writes on to, and everything they write
+//! to it on to the device.
+
+use std::{
+    io::{self, Write as _},
+    os::fd::{AsFd, OwnedFd},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use nix::{
+    fcntl::OFlag,
+    pty::OpenptyResult,
+    sys::{
+        stat::Mode,
+        termios::{self, BaudRate, SetArg},
+    },
+};
+use tokio::io::unix::AsyncFd;
+
+/// Parses a baud rate given on the command line.
+pub fn parse_baud_rate(s: &str) -> Result<BaudRate, String> {
+    Ok(match s.parse::<u32>().map_err(|e| e.to_string())? {
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        460800 => BaudRate::B460800,
+        921600 => BaudRate::B921600,
+        other => return Err(format!("unsupported baud rate {other}")),
+    })
+}
+
+/// How to capture a device's console.
+#[derive(Debug, Clone)]
+pub struct ConsoleConfig {
+    pub baud_rate: BaudRate,
+    /// Jobs whose device doesn't write to its console for this long, once it has been flashed or
+    /// has written something, are stopped.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Console output captured from a device, prefixed line by line with the time since the capture
+/// started.
+pub struct Console {
+    device: AsyncFd<OwnedFd>,
+    /// Master side of the pty the job's scripts use in place of the device's serial port
+    pty: AsyncFd<OwnedFd>,
+    /// Kept open so that the pty doesn't hang up whenever no script has it open
+    _pty_slave: OwnedFd,
+    pty_path: PathBuf,
+    /// What the scripts wrote that hasn't been written to the device yet
+    to_device: Vec<u8>,
+    start: Instant,
+    output: Vec<u8>,
+    max_bytes: usize,
+    truncated: bool,
+    at_line_start: bool,
+}
+impl Console {
+    /// Opens the serial port at `path` in raw mode, and a pty for the job's scripts to use in its
+    /// place. At most `max_bytes` of output are kept.
+    pub fn open(path: &Path, baud_rate: BaudRate, max_bytes: usize) -> io::Result<Self> {
+        let device = nix::fcntl::open(
+            path,
+            OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        let mut attrs = termios::tcgetattr(&device)?;
+        termios::cfmakeraw(&mut attrs);
+        termios::cfsetspeed(&mut attrs, baud_rate)?;
+        termios::tcsetattr(&device, SetArg::TCSANOW, &attrs)?;
+
+        let OpenptyResult { master, slave } = nix::pty::openpty(None, None)?;
+        let mut attrs = termios::tcgetattr(&slave)?;
+        termios::cfmakeraw(&mut attrs);
+        termios::tcsetattr(&slave, SetArg::TCSANOW, &attrs)?;
+        let pty_path = nix::unistd::ttyname(slave.as_fd())?;
+        nix::fcntl::fcntl(&master, nix::fcntl::FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        nix::fcntl::fcntl(&master, nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC))?;
+        nix::fcntl::fcntl(&slave, nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC))?;
+
+        Ok(Self {
+            device: AsyncFd::new(device)?,
+            pty: AsyncFd::new(master)?,
+            _pty_slave: slave,
+            pty_path,
+            to_device: vec![],
+            start: Instant::now(),
+            output: vec![],
+            max_bytes,
+            truncated: false,
+            at_line_start: true,
+        })
+    }
+
+    /// The pty the job's scripts should use as the device's serial port.
+    pub fn path(&self) -> &Path {
+        &self.pty_path
+    }
+
+    /// Waits for the device to write something, captures it and passes it on to the pty,
+    /// meanwhile passing on what the scripts write to the pty to the device. Returns `false` once
+    /// the device has hung up (e.g. it was unplugged), after which there is nothing more to read.
+    ///
+    /// Cancel-safe: no output is lost if the returned future is dropped.
+    pub async fn read(&mut self) -> io::Result<bool> {
+        let mut from_device = [0; 4096];
+        let mut from_pty = [0; 4096];
+        loop {
+            tokio::select! {
+                res = read_from(&self.device, &mut from_device) => match res {
+                    Ok(0) => return Ok(false),
+                    Ok(n) => {
+                        self.capture(&from_device[..n]);
+                        // like a serial port, the pty drops what the scripts don't read in time
+                        let _ = nix::unistd::write(self.pty.get_ref(), &from_device[..n]);
+                        return Ok(true);
+                    }
+                    // ttys report a hangup as EIO
+                    Err(e) if e.raw_os_error() == Some(nix::libc::EIO) => return Ok(false),
+                    Err(e) => return Err(e),
+                },
+                res = read_from(&self.pty, &mut from_pty), if self.to_device.is_empty() => {
+                    let n = res?;
+                    self.to_device.extend_from_slice(&from_pty[..n]);
+                }
+                res = write_to(&self.device, &self.to_device), if !self.to_device.is_empty() => {
+                    let n = res?;
+                    self.to_device.drain(..n);
+                }
+            }
+        }
+    }
+
+    /// Adds a note to the captured output, on a line of its own.
+    pub fn note(&mut self, note: &str) {
+        if !self.at_line_start {
+            self.capture(b"\n");
+        }
+        self.capture(format!("{note}\n").as_bytes());
+    }
+
+    fn capture(&mut self, data: &[u8]) {
+        let elapsed = self.start.elapsed();
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if self.at_line_start {
+                let stamp = format!("[{:5}.{:03}] ", elapsed.as_secs(), elapsed.subsec_millis());
+                self.push(stamp.as_bytes());
+            }
+            self.push(line);
+            self.at_line_start = line.ends_with(b"\n");
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        let room = self.max_bytes.saturating_sub(self.output.len());
+        if data.len() > room {
+            self.truncated = true;
+        }
+        self.output.extend_from_slice(&data[..data.len().min(room)]);
+    }
+
+    /// Appends the captured output to a job's log, after a marker that separates it from what the
+    /// job's scripts wrote.
+    pub fn append_to(self, log: &mut Vec<u8>, serial: &Path) {
+        if !log.is_empty() && !log.ends_with(b"\n") {
+            log.push(b'\n');
+        }
+        let _ = writeln!(log, "\n===== serial console output ({}) =====", serial.display());
+        log.extend_from_slice(&self.output);
+        if !self.at_line_start {
+            log.push(b'\n');
+        }
+        if self.truncated {
+            let _ = writeln!(log, "===== serial console output truncated =====");
+        }
+    }
+}
+
+/// Reads from a non-blocking fd once it is readable.
+async fn read_from(fd: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = fd.readable().await?;
+        match guard.try_io(|fd| Ok(nix::unistd::read(fd.get_ref(), buf)?)) {
+            Ok(res) => return res,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+/// Writes to a non-blocking fd once it is writable. Returns how much was written.
+async fn write_to(fd: &AsyncFd<OwnedFd>, data: &[u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = fd.writable().await?;
+        match guard.try_io(|fd| Ok(nix::unistd::write(fd.get_ref(), data)?)) {
+            Ok(res) => return res,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::fd::AsFd, path::PathBuf};
+
+    use nix::pty::{OpenptyResult, openpty};
+
+    use super::*;
+
+    /// A pty pair standing in for a device: the returned path is the device's serial port, and
+    /// whatever is written to the returned fd is what the device writes to its console.
+    fn fake_device() -> (OwnedFd, PathBuf, OwnedFd) {
+        let OpenptyResult { master, slave } = openpty(None, None).unwrap();
+        let path = nix::unistd::ttyname(slave.as_fd()).unwrap();
+        (master, path, slave)
+    }
+
+    fn captured(console: Console) -> String {
+        let mut log = vec![];
+        console.append_to(&mut log, Path::new("/dev/ttyFAKE"));
+        String::from_utf8(log).unwrap()
+    }
+
+    #[tokio::test]
+    async fn captures_timestamped_lines() {
+        let (master, path, _slave) = fake_device();
+        let mut console = Console::open(&path, BaudRate::B115200, 1 << 16).unwrap();
+
+        nix::unistd::write(&master, b"hello\nwor").unwrap();
+        while console.read().await.unwrap() && !console.output.ends_with(b"wor") {}
+        nix::unistd::write(&master, b"ld\n").unwrap();
+        while console.read().await.unwrap() && !console.output.ends_with(b"\n") {}
+
+        let log = captured(console);
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines[1], "===== serial console output (/dev/ttyFAKE) =====");
+        assert!(lines[2].starts_with('[') && lines[2].ends_with("] hello"), "{log}");
+        assert!(lines[3].ends_with("] world"), "{log}");
+        assert_eq!(lines.len(), 4, "{log}");
+    }
+
+    #[tokio::test]
+    async fn passes_data_between_device_and_scripts() {
+        let (device, path, _slave) = fake_device();
+        nix::fcntl::fcntl(&device, nix::fcntl::FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+        let mut console = Console::open(&path, BaudRate::B115200, 1 << 16).unwrap();
+        let script = nix::fcntl::open(console.path(), OFlag::O_RDWR | OFlag::O_NOCTTY, Mode::empty()).unwrap();
+
+        nix::unistd::write(&script, b"ping").unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut received = vec![];
+            while !received.ends_with(b"ping") {
+                let _ = tokio::time::timeout(Duration::from_millis(10), console.read()).await;
+                let mut buf = [0; 16];
+                if let Ok(n) = nix::unistd::read(&device, &mut buf) {
+                    received.extend_from_slice(&buf[..n]);
+                }
+            }
+            received
+        });
+        assert_eq!(received.await.expect("the device should get what the script wrote"), b"ping");
+
+        nix::unistd::write(&device, b"pong").unwrap();
+        while console.read().await.unwrap() && !console.output.ends_with(b"pong") {}
+        let mut buf = [0; 4];
+        nix::unistd::read(&script, &mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+        assert!(captured(console).ends_with("] pong\n"));
+    }
+
+    #[tokio::test]
+    async fn hangup_ends_capture() {
+        let (master, path, slave) = fake_device();
+        let mut console = Console::open(&path, BaudRate::B115200, 1 << 16).unwrap();
+        nix::unistd::write(&master, b"bye").unwrap();
+        while console.read().await.unwrap() && !console.output.ends_with(b"bye") {}
+        drop(master);
+        drop(slave);
+
+        let read = tokio::time::timeout(Duration::from_secs(5), async {
+            while console.read().await.unwrap() {}
+        });
+        read.await.expect("capture should stop when the device hangs up");
+        console.note("device hung up");
+
+        let log = captured(console);
+        assert!(log.contains("] bye\n"), "{log}");
+        assert!(log.ends_with("] device hung up\n"), "{log}");
+    }
+
+    #[tokio::test]
+    async fn output_is_truncated_at_limit() {
+        let (master, path, _slave) = fake_device();
+        let mut console = Console::open(&path, BaudRate::B115200, 16).unwrap();
+        nix::unistd::write(&master, b"0123456789\n0123456789\n").unwrap();
+        while console.read().await.unwrap() && console.output.len() < 16 {}
+
+        assert_eq!(console.output.len(), 16);
+        assert!(captured(console).ends_with("===== serial console output truncated =====\n"));
+    }
+}
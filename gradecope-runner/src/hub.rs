@@ -0,0 +1,216 @@
+//! Power cycling devices through the port of the USB hub they are plugged into, with hub class
+//! requests (USB 2.0 spec, section 11.24).
+
+use std::time::Duration;
+
+/// `bmRequestType` of port requests: class-specific, addressed to a port ("other").
+const PORT_REQUEST_OUT: u8 = 0x23;
+const PORT_REQUEST_IN: u8 = 0xa3;
+
+const GET_STATUS: u8 = 0x00;
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+
+const PORT_POWER: u16 = 8;
+
+/// Power bit of `wPortStatus`; USB 3 hubs moved it up one bit.
+const PORT_STAT_POWER: u16 = 1 << 8;
+const PORT_STAT_SS_POWER: u16 = 1 << 9;
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The requests power cycling makes of a hub.
+pub trait Hub {
+    fn set_port_power(&self, port: u8, on: bool) -> yusb::Result<()>;
+    fn port_powered(&self, port: u8) -> yusb::Result<bool>;
+}
+
+/// A hub opened through libusb.
+pub struct UsbHub {
+    handle: yusb::DeviceHandle,
+    superspeed: bool,
+}
+impl UsbHub {
+    pub fn open(device: &yusb::Device) -> yusb::Result<Self> {
+        let superspeed = device.device_descriptor()?.usb_version().major() >= 3;
+        Ok(Self {
+            handle: device.open()?,
+            superspeed,
+        })
+    }
+}
+impl Hub for UsbHub {
+    fn set_port_power(&self, port: u8, on: bool) -> yusb::Result<()> {
+        let request = if on { SET_FEATURE } else { CLEAR_FEATURE };
+        self.handle.write_control(
+            PORT_REQUEST_OUT,
+            request,
+            PORT_POWER,
+            port.into(),
+            &[],
+            CONTROL_TIMEOUT,
+        )?;
+        Ok(())
+    }
+
+    fn port_powered(&self, port: u8) -> yusb::Result<bool> {
+        // wPortStatus, then wPortChange
+        let mut status = [0; 4];
+        let n = self.handle.read_control(
+            PORT_REQUEST_IN,
+            GET_STATUS,
+            0,
+            port.into(),
+            &mut status,
+            CONTROL_TIMEOUT,
+        )?;
+        if n < 2 {
+            return Err(yusb::Error::Io);
+        }
+        let port_status = u16::from_le_bytes([status[0], status[1]]);
+        let power_bit = if self.superspeed {
+            PORT_STAT_SS_POWER
+        } else {
+            PORT_STAT_POWER
+        };
+        Ok(port_status & power_bit != 0)
+    }
+}
+
+/// Turns `port` off for `off_delay`, then back on, checking that the hub actually switched it
+/// each time. Hubs that gang power across ports, or don't switch it at all, still acknowledge the
+/// requests, so without the check they would silently not power cycle anything.
+///
+/// The port is powered back on even if powering it off went wrong partway.
+pub fn power_cycle(hub: &impl Hub, port: u8, off_delay: Duration) -> eyre::Result<()> {
+    hub.set_port_power(port, false)
+        .map_err(|e| eyre::eyre!("failed to power off port {port}: {e}"))?;
+
+    let powered_off = hub
+        .port_powered(port)
+        .map_err(|e| eyre::eyre!("failed to get status of port {port}: {e}"))
+        .and_then(|powered| {
+            eyre::ensure!(
+                !powered,
+                "hub did not power off port {port}; it may not support per-port power switching"
+            );
+            std::thread::sleep(off_delay);
+            Ok(())
+        });
+
+    hub.set_port_power(port, true)
+        .map_err(|e| eyre::eyre!("failed to power on port {port}: {e}"))?;
+    powered_off?;
+    let powered = hub
+        .port_powered(port)
+        .map_err(|e| eyre::eyre!("failed to get status of port {port}: {e}"))?;
+    eyre::ensure!(powered, "hub did not power port {port} back on");
+    Ok(())
+}
+
+/// How to power cycle a device before it is used.
+#[derive(Debug, Clone)]
+pub struct PowerCycle {
+    /// The hub the device is plugged into.
+    pub hub: yusb::Device,
+    pub port: u8,
+    /// How long the port is left unpowered.
+    pub off_delay: Duration,
+    /// How long the device is given to boot once it is powered back on.
+    pub boot_delay: Duration,
+}
+impl PowerCycle {
+    pub async fn run(&self) -> eyre::Result<()> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let hub = UsbHub::open(&this.hub)
+                .map_err(|e| eyre::eyre!("failed to open USB hub: {e}"))?;
+            power_cycle(&hub, this.port, this.off_delay)
+        })
+        .await??;
+        tokio::time::sleep(self.boot_delay).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// Records the power requests made of it, and fails or ignores them as configured.
+    #[derive(Default)]
+    struct MockHub {
+        powered: RefCell<bool>,
+        requests: RefCell<Vec<(u8, bool)>>,
+        /// Acknowledges power requests without switching anything, like a ganged hub.
+        ignores_requests: bool,
+        fails_power_off: bool,
+        fails_power_on: bool,
+    }
+    impl MockHub {
+        fn new() -> Self {
+            Self {
+                powered: RefCell::new(true),
+                ..Self::default()
+            }
+        }
+    }
+    impl Hub for MockHub {
+        fn set_port_power(&self, port: u8, on: bool) -> yusb::Result<()> {
+            self.requests.borrow_mut().push((port, on));
+            if (on && self.fails_power_on) || (!on && self.fails_power_off) {
+                return Err(yusb::Error::Pipe);
+            }
+            if !self.ignores_requests {
+                *self.powered.borrow_mut() = on;
+            }
+            Ok(())
+        }
+
+        fn port_powered(&self, _port: u8) -> yusb::Result<bool> {
+            Ok(*self.powered.borrow())
+        }
+    }
+
+    #[test]
+    fn powers_port_off_then_on() {
+        let hub = MockHub::new();
+        power_cycle(&hub, 3, Duration::ZERO).unwrap();
+        assert_eq!(*hub.requests.borrow(), [(3, false), (3, true)]);
+        assert!(*hub.powered.borrow());
+    }
+
+    #[test]
+    fn hub_that_does_not_switch_power_is_an_error() {
+        let hub = MockHub {
+            ignores_requests: true,
+            ..MockHub::new()
+        };
+        let err = power_cycle(&hub, 2, Duration::ZERO).unwrap_err();
+        assert!(err.to_string().contains("did not power off"), "{err}");
+        assert_eq!(*hub.requests.borrow(), [(2, false), (2, true)]);
+    }
+
+    #[test]
+    fn failed_power_off_leaves_port_alone() {
+        let hub = MockHub {
+            fails_power_off: true,
+            ..MockHub::new()
+        };
+        assert!(power_cycle(&hub, 1, Duration::ZERO).is_err());
+        assert_eq!(*hub.requests.borrow(), [(1, false)]);
+        assert!(*hub.powered.borrow());
+    }
+
+    #[test]
+    fn failed_power_on_is_an_error() {
+        let hub = MockHub {
+            fails_power_on: true,
+            ..MockHub::new()
+        };
+        let err = power_cycle(&hub, 4, Duration::ZERO).unwrap_err();
+        assert!(err.to_string().contains("failed to power on"), "{err}");
+    }
+}
@@ -1,36 +1,117 @@
-#![feature(iterator_try_collect, iter_intersperse, try_blocks)]
+#![feature(iterator_try_collect, iter_intersperse)]
 
 use std::{
     fmt::Display,
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 
 use clap::Parser;
-use uuid::Uuid;
 
 mod connection;
+mod console;
+mod hub;
 mod runner;
+mod tls;
+
+/// How the runner finds out about jobs to run and cancel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DispatchMode {
+    /// The switchboard tells the runner as soon as there are any.
+    Push,
+    /// The runner asks the switchboard every `--poll-interval-ms`.
+    Poll,
+}
 
 #[derive(Debug, Parser)]
 pub struct Opts {
     /// Interval, in milliseconds, at which the runner should poll the server for jobs and/or
-    /// cancellations.
+    /// cancellations if `--dispatch poll` is used, and check whether devices are due for a health
+    /// check.
     #[arg(long, default_value_t = 100)]
     poll_interval_ms: u64,
 
+    /// How the runner finds out about jobs to run and cancel.
+    #[arg(long, value_enum, default_value_t = DispatchMode::Push)]
+    dispatch: DispatchMode,
+
     #[arg(long, required = true)]
     remote: String,
 
-    #[arg(long, required = true)]
-    id: String,
+    /// Name the runner registers under. Defaults to the common name in `--tls-cert`, which the
+    /// switchboard requires it to be.
+    #[arg(long, required_unless_present = "tls_cert")]
+    id: Option<String>,
+
+    /// CA certificate that the switchboard's certificate must be signed by. Makes the runner
+    /// connect over mutual TLS.
+    #[arg(long, requires_all = ["tls_cert", "tls_key"])]
+    tls_ca: Option<PathBuf>,
 
-    // Expects ttyXYZ:<bus>-<ports>.*
+    /// Certificate the runner presents to the switchboard, with `--tls-ca`.
+    #[arg(long, requires = "tls_ca")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of `--tls-cert`.
+    #[arg(long, requires = "tls_ca")]
+    tls_key: Option<PathBuf>,
+
+    // Expects ttyXYZ:<bus>-<ports>.*[@<class>]
     #[arg(short = 'd', long = "device", required = true)]
     devices: Vec<String>,
 
     #[arg(long, required = true)]
     test_runner: PathBuf,
+
+    /// Number of consecutive errored jobs after which a device is quarantined. A device is also
+    /// quarantined as soon as it fails to power cycle or a cleanup script fails on it.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    quarantine_after: u32,
+
+    /// Script that checks whether a device is fit to run jobs, invoked like the job scripts but
+    /// with empty job arguments. Defaults to `healthcheck.sh` in the test runner directory if there
+    /// is one; otherwise, a device is healthy if its serial port and USB device are present.
+    #[arg(long)]
+    healthcheck: Option<PathBuf>,
+
+    /// How often, in seconds, idle and quarantined devices are health-checked. Devices are also
+    /// checked at startup and as soon as they are quarantined, and only run jobs once a check
+    /// passes.
+    #[arg(long, default_value_t = 300)]
+    healthcheck_interval_secs: u64,
+
+    /// How long, in seconds, a health check may take before the device is considered unhealthy.
+    #[arg(long, default_value_t = 120)]
+    healthcheck_timeout_secs: u64,
+
+    /// Don't power cycle devices through their USB hub before each job and health check, e.g. if
+    /// their hubs can't switch power per port.
+    #[arg(long)]
+    no_power_cycle: bool,
+
+    /// How long, in milliseconds, a device is left unpowered when it is power cycled.
+    #[arg(long, default_value_t = 2000)]
+    power_off_ms: u64,
+
+    /// How long, in milliseconds, a device is given to boot after it is powered back on.
+    #[arg(long, default_value_t = 1000)]
+    power_on_ms: u64,
+
+    /// Capture what devices write to their serial port while running a job, and add it to the
+    /// job's log. The job's `.run.sh` script then gets a pty that passes data to and from the
+    /// device in place of its serial port.
+    #[arg(long)]
+    capture_console: bool,
+
+    /// Baud rate of devices' serial consoles.
+    #[arg(long, default_value = "115200", value_parser = console::parse_baud_rate)]
+    console_baud: nix::sys::termios::BaudRate,
+
+    /// With `--capture-console`, stop jobs whose device writes nothing to its console for this
+    /// many seconds, once it has been flashed or has written something.
+    #[arg(long)]
+    console_idle_timeout_secs: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -38,21 +119,31 @@ struct DeviceOpt {
     serial: PathBuf,
     bus: u8,
     ports: Vec<u8>,
+    class: Option<String>,
 }
 impl FromStr for DeviceOpt {
     type Err = eyre::Report;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, class) = match s.split_once('@') {
+            Some((s, class)) => (s, Some(class.to_owned())),
+            None => (s, None),
+        };
         let (serial, usb) = s.split_once(':').unwrap();
         let serial = Path::new("/dev").join(serial);
         eyre::ensure!(serial.exists(), "/dev/{} does not exist", serial.display());
         let (bus, ports) = usb.split_once('-').unwrap();
-        let bus = u8::from_str_radix(bus, 10)?;
+        let bus = bus.parse::<u8>()?;
         let ports = ports
             .split('.')
-            .map(|s| u8::from_str_radix(s, 10))
+            .map(|s| s.parse::<u8>())
             .try_collect()?;
-        Ok(Self { serial, bus, ports })
+        Ok(Self {
+            serial,
+            bus,
+            ports,
+            class,
+        })
     }
 }
 impl Display for DeviceOpt {
@@ -67,7 +158,11 @@ impl Display for DeviceOpt {
                 .map(|x| format!("{x}"))
                 .intersperse(".".into())
                 .collect::<String>()
-        )
+        )?;
+        if let Some(class) = &self.class {
+            write!(f, "@{class}")?;
+        }
+        Ok(())
     }
 }
 
@@ -75,6 +170,12 @@ impl Display for DeviceOpt {
 pub struct DeviceCtl {
     pub serial: PathBuf,
     pub usb_dev: yusb::Device,
+    /// Only jobs that require no particular device class, or this one, run on this device
+    pub class: Option<String>,
+    /// How to power cycle the device, if it should be
+    pub power_cycle: Option<hub::PowerCycle>,
+    /// How to capture the device's serial console, if it should be
+    pub console: Option<console::ConsoleConfig>,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -83,6 +184,22 @@ async fn main() {
 
     let opts = Opts::parse();
 
+    let id = match (&opts.id, &opts.tls_cert) {
+        (id, Some(cert)) => match tls::certificate_name(cert) {
+            Ok(name) if id.as_ref().is_none_or(|id| *id == name) => name,
+            Ok(name) => {
+                tracing::error!("--id doesn't match the name in the certificate, {name:?}");
+                return;
+            }
+            Err(e) => {
+                tracing::error!("Failed to read runner certificate: {e:?}");
+                return;
+            }
+        },
+        (Some(id), None) => id.clone(),
+        (None, None) => unreachable!("clap requires --id without --tls-cert"),
+    };
+
     let mut opt_devices: Vec<DeviceOpt> = opts
         .devices
         .iter()
@@ -105,6 +222,7 @@ async fn main() {
                 serial,
                 bus: _,
                 ports: _,
+                class,
             } = opt_devices.swap_remove(i);
             println!(
                 "{}@{}-{:?}",
@@ -112,9 +230,29 @@ async fn main() {
                 dev.bus_number(),
                 dev.port_numbers()
             );
+            let power_cycle = if opts.no_power_cycle {
+                None
+            } else if let Some(hub) = dev.get_parent() {
+                Some(hub::PowerCycle {
+                    hub,
+                    port: dev.port_number(),
+                    off_delay: Duration::from_millis(opts.power_off_ms),
+                    boot_delay: Duration::from_millis(opts.power_on_ms),
+                })
+            } else {
+                tracing::warn!("Device {} is not on a hub, so it can't be power cycled", serial.display());
+                None
+            };
+            let console = opts.capture_console.then(|| console::ConsoleConfig {
+                baud_rate: opts.console_baud,
+                idle_timeout: opts.console_idle_timeout_secs.map(Duration::from_secs),
+            });
             ctl_devices.push(DeviceCtl {
                 serial,
                 usb_dev: dev,
+                class,
+                power_cycle,
+                console,
             });
         }
     }
@@ -128,9 +266,14 @@ async fn main() {
 
     let mut devices = vec![];
     for dev_ctl in ctl_devices {
-        devices.push((Uuid::new_v4(), dev_ctl));
+        let device_id = gradecope_proto::runner::device_id(
+            &id,
+            dev_ctl.usb_dev.bus_number(),
+            &dev_ctl.usb_dev.port_numbers().unwrap_or_default(),
+        );
+        devices.push((device_id, dev_ctl));
     }
-    if let Err(e) = connection::connect(opts, devices).await {
+    if let Err(e) = connection::connect(opts, id, devices).await {
         tracing::error!("Connection worker failed with error: {e:?}");
     }
 }
@@ -1,72 +1,426 @@
 use std::{
+    io::SeekFrom,
     path::{Path, PathBuf},
     time::Duration,
 };
 
 use chrono::Utc;
-use gradecope_proto::runner::{JobResult, JobSpec, JobTermination, Log};
-use tokio::io::AsyncReadExt;
+use gradecope_proto::runner::{
+    JobPhase, JobResult, JobSpec, JobTermination, Log, LogChunk, PhaseEvent, TestCase,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt as _},
+    sync::mpsc,
+};
 use uuid::Uuid;
 
+use crate::console::Console;
+
+/// How often the log of a running job is sent to the switchboard.
+const LOG_STREAM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upper bound on the size of the results file a test script may write.
+const MAX_RESULTS_BYTES: u64 = 1 << 20;
+
+/// How much of the end of a failed health check's log is read to explain the failure.
+const MAX_HEALTHCHECK_LOG_BYTES: u64 = 64 << 10;
+
+/// Upper bound on how much a test script may write to its phases file.
+const MAX_PHASES_BYTES: u64 = 4 << 10;
+
+/// What a worker tells the dispatcher about its job while it runs.
+#[derive(Debug)]
+pub enum WorkerMsg {
+    LogChunk(LogChunk),
+    Phase(PhaseEvent),
+}
+
+/// Parses the results file written by a test script, if it wrote one.
+///
+/// A missing, oversized or malformed results file only loses the per-test breakdown; the job's
+/// result is still decided by the script's exit code.
+async fn read_test_cases(results_file: &mut async_tempfile::TempFile) -> Vec<TestCase> {
+    let mut v = vec![];
+    match results_file.take(MAX_RESULTS_BYTES + 1).read_to_end(&mut v).await {
+        Ok(0) => vec![],
+        Ok(n) if n as u64 > MAX_RESULTS_BYTES => {
+            tracing::warn!("Ignoring results file larger than {MAX_RESULTS_BYTES} bytes");
+            vec![]
+        }
+        Ok(_) => serde_json::from_slice(&v).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring malformed results file: {e}");
+            vec![]
+        }),
+        Err(e) => {
+            tracing::error!("Failed to read results file: {e:?}");
+            vec![]
+        }
+    }
+}
+
+/// Reads from a job's console, if it is being captured.
+async fn read_console(console: &mut Option<Console>) -> std::io::Result<bool> {
+    match console {
+        Some(console) => console.read().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The phases a job has reached. Each is reported as soon as it is reached, and all of them again
+/// with the job's termination.
+struct Phases {
+    job_id: Uuid,
+    events: Vec<PhaseEvent>,
+    /// Read side of the phases file, which the test script appends a line to for each phase it
+    /// reaches, if it could be opened
+    file: Option<tokio::fs::File>,
+    /// How much of the phases file has been read
+    offset: u64,
+    /// An incomplete last line of the phases file
+    partial_line: Vec<u8>,
+}
+impl Phases {
+    fn new(job_id: Uuid) -> Self {
+        Self {
+            job_id,
+            events: vec![],
+            file: None,
+            offset: 0,
+            partial_line: vec![],
+        }
+    }
+
+    fn has_reached(&self, phase: JobPhase) -> bool {
+        self.events.iter().any(|event| event.phase == phase)
+    }
+
+    /// Records that the job reached `phase` just now, unless it already did.
+    fn reached(&mut self, phase: JobPhase, updates: &mpsc::Sender<WorkerMsg>) {
+        if self.has_reached(phase) {
+            return;
+        }
+        let event = PhaseEvent {
+            job_id: self.job_id,
+            phase,
+            at: Utc::now(),
+        };
+        self.events.push(event.clone());
+        // Dropped rather than waited on if the dispatcher is backed up (e.g. while disconnected),
+        // so as not to hold up the job; every phase goes with the termination anyway.
+        let _ = updates.try_send(WorkerMsg::Phase(event));
+    }
+
+    /// Records the phases the test script wrote to the phases file since the last call. Only the
+    /// phases that happen inside the script are accepted from it.
+    async fn read_file(&mut self, updates: &mpsc::Sender<WorkerMsg>) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let budget = MAX_PHASES_BYTES.saturating_sub(self.offset);
+        let mut data = std::mem::take(&mut self.partial_line);
+        match file.take(budget).read_to_end(&mut data).await {
+            Ok(n) => self.offset += n as u64,
+            Err(e) => {
+                tracing::warn!("Stopped reading phases file: {e:?}");
+                self.file = None;
+                return;
+            }
+        }
+        let mut lines: Vec<&[u8]> = data.split(|&b| b == b'\n').collect();
+        // whatever follows the last newline is still being written
+        let partial_line = lines.pop().unwrap_or_default().to_vec();
+        for line in lines {
+            let phase = match String::from_utf8_lossy(line).trim() {
+                "repo_fetched" => JobPhase::RepoFetched,
+                "build_done" => JobPhase::BuildDone,
+                "device_flashed" => JobPhase::DeviceFlashed,
+                "" => continue,
+                other => {
+                    tracing::warn!("Ignoring unknown phase {other:?} in phases file");
+                    continue;
+                }
+            };
+            self.reached(phase, updates);
+        }
+        self.partial_line = partial_line;
+    }
+}
+
+/// Read side of a job's log file, tracking how much of it has been streamed.
+struct LogTail {
+    file: tokio::fs::File,
+    offset: u64,
+}
+impl LogTail {
+    /// Sends whatever was written to the log since the last call, up to the job's log limit, unless
+    /// the dispatcher is backed up.
+    async fn stream(
+        &mut self,
+        spec: &JobSpec,
+        updates: &mpsc::Sender<WorkerMsg>,
+    ) -> eyre::Result<()> {
+        let budget = u64::from(spec.max_log_bytes).saturating_sub(self.offset);
+        let mut data = vec![];
+        (&mut self.file).take(budget).read_to_end(&mut data).await?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let len = data.len() as u64;
+        let chunk = WorkerMsg::LogChunk(LogChunk {
+            job_id: spec.id,
+            offset: self.offset,
+            data,
+        });
+        match updates.try_send(chunk) {
+            Ok(()) => self.offset += len,
+            // Waiting for room would hold up the job's timeouts and cancellation, and dropping
+            // the chunk would leave a gap the switchboard refuses to append after; so it's read
+            // again next time instead.
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.file.seek(SeekFrom::Start(self.offset)).await?;
+            }
+            Err(e @ mpsc::error::TrySendError::Closed(_)) => Err(e)?,
+        }
+        Ok(())
+    }
+}
+
+/// Passes the arguments every test runner script is invoked with: the device's ID, the job's ID,
+/// repository and commit, the log file, the device's serial port, its port on its USB hub, the
+/// hub's path, the results file, and the phases file. The serial port is `serial` rather than the
+/// device's own while the runner captures the device's console.
+///
+/// Health checks aren't tied to a job, so they get empty job arguments and no results or phases
+/// file, but otherwise the same arguments, so that they can share code with the job scripts.
+#[allow(clippy::too_many_arguments)]
+fn setup_args(
+    cmd: &mut tokio::process::Command,
+    worker_id: Uuid,
+    dev_ctl: &crate::DeviceCtl,
+    serial: &Path,
+    spec: Option<&JobSpec>,
+    logfile: &Path,
+    results_file: Option<&Path>,
+    phases_file: Option<&Path>,
+) -> eyre::Result<()> {
+    cmd.arg(worker_id.to_string());
+    match spec {
+        Some(spec) => {
+            cmd.arg(spec.id.to_string());
+            cmd.arg(&spec.repo_path);
+            cmd.arg(&spec.commit_hash);
+        }
+        None => {
+            cmd.args(["", "", ""]);
+        }
+    }
+    cmd.arg(logfile);
+    cmd.arg(serial);
+    let port_numbers = dev_ctl
+        .usb_dev
+        .port_numbers()
+        .map_err(|e| eyre::eyre!("failed to get the device's USB port: {e}"))?;
+    let (last_port, port_prefix) = port_numbers
+        .split_last()
+        .ok_or_else(|| eyre::eyre!("device is not on a USB port"))?;
+    let last_port_str = format!("{last_port}");
+    let mut port_prefix_str = format!("{}-", dev_ctl.usb_dev.bus_number());
+    for s in port_prefix.iter().map(Some).intersperse(None) {
+        if let Some(s) = s {
+            port_prefix_str.push_str(&format!("{s}"));
+        } else {
+            port_prefix_str.push('.');
+        }
+    }
+    cmd.arg(last_port_str);
+    cmd.arg(port_prefix_str);
+    if let Some(results_file) = results_file {
+        cmd.arg(results_file);
+    }
+    if let Some(phases_file) = phases_file {
+        cmd.arg(phases_file);
+    }
+    cmd.current_dir(
+        std::env::current_dir().map_err(|e| eyre::eyre!("failed to get the working directory: {e}"))?,
+    );
+    Ok(())
+}
+
+/// Checks whether a device is fit to run jobs, with the given health check script if there is one,
+/// or otherwise by checking that its serial port exists and that it is still on its USB port. The
+/// device is power cycled first, if it is set up to be, and failing that makes it unhealthy too.
+///
+/// Returns why the device is unhealthy if it is.
+#[tracing::instrument(skip(dev_ctl, healthcheck))]
+pub async fn check_health(
+    worker_id: Uuid,
+    dev_ctl: crate::DeviceCtl,
+    healthcheck: Option<PathBuf>,
+    timeout: Duration,
+) -> Result<(), String> {
+    if let Some(power_cycle) = &dev_ctl.power_cycle {
+        power_cycle
+            .run()
+            .await
+            .map_err(|e| format!("failed to power cycle device: {e}"))?;
+    }
+
+    let Some(healthcheck) = healthcheck else {
+        if !dev_ctl.serial.exists() {
+            return Err(format!("{} does not exist", dev_ctl.serial.display()));
+        }
+        let bus = dev_ctl.usb_dev.bus_number();
+        let ports = dev_ctl.usb_dev.port_numbers().unwrap_or_default();
+        let present = yusb::devices()
+            .map_err(|e| format!("failed to list USB devices: {e}"))?
+            .iter()
+            .any(|dev| dev.bus_number() == bus && dev.port_numbers().is_ok_and(|p| p == ports));
+        return if present {
+            Ok(())
+        } else {
+            Err("device is no longer on its USB port".to_owned())
+        };
+    };
+
+    let mut logfile = async_tempfile::TempFile::new()
+        .await
+        .map_err(|e| format!("failed to create log file: {e}"))?;
+    let mut cmd = tokio::process::Command::new("bash");
+    cmd.arg(&healthcheck);
+    cmd.kill_on_drop(true);
+    setup_args(
+        &mut cmd,
+        worker_id,
+        &dev_ctl,
+        &dev_ctl.serial,
+        None,
+        logfile.file_path(),
+        None,
+        None,
+    )
+    .map_err(|e| format!("failed to set up health check: {e}"))?;
+    tracing::debug!("Running {cmd:?}");
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format!("failed to spawn {}: {e}", healthcheck.display()))?;
+    let status = match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(Ok(status)) => status,
+        Ok(Err(e)) => return Err(format!("failed to wait() for health check: {e}")),
+        Err(_) => return Err(format!("health check timed out after {}s", timeout.as_secs())),
+    };
+    if status.success() {
+        return Ok(());
+    }
+
+    // The last line of the log is most likely to say what went wrong
+    match last_line(&mut logfile, MAX_HEALTHCHECK_LOG_BYTES).await {
+        Some(line) => Err(format!("health check failed ({status}): {line}")),
+        None => Err(format!("health check failed ({status})")),
+    }
+}
+
+/// The last non-blank line of `file`, trimmed, out of its last `max_bytes`.
+async fn last_line(file: &mut tokio::fs::File, max_bytes: u64) -> Option<String> {
+    let len = file.metadata().await.ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(max_bytes))).await.ok()?;
+    let mut data = vec![];
+    file.take(max_bytes).read_to_end(&mut data).await.ok()?;
+    let data = String::from_utf8_lossy(&data);
+    let line = data.lines().rev().find(|line| !line.trim().is_empty())?;
+    Some(line.trim().to_owned())
+}
+
 #[tracing::instrument(
     fields(
         job.id = %spec.id, job.repo = spec.repo_path, job.commit = spec.commit_hash,
         job.spec = spec.job_spec, worker.id = %worker_id),
-    skip(test_runner, cancel, output)
+    skip(test_runner, cancel, updates, output)
 )]
 pub async fn run_job(
     worker_id: Uuid,
     dev_ctl: crate::DeviceCtl,
     test_runner: PathBuf,
     spec: JobSpec,
-    mut cancel: tokio::sync::oneshot::Receiver<()>,
+    mut cancel: tokio::sync::oneshot::Receiver<Option<String>>,
+    updates: mpsc::Sender<WorkerMsg>,
     output: tokio::sync::oneshot::Sender<JobTermination>,
 ) {
-    let setup_args = |cmd: &mut tokio::process::Command, logfile: &Path| {
-        cmd.arg(worker_id.to_string());
-        cmd.arg(spec.id.to_string());
-        cmd.arg(&spec.repo_path);
-        cmd.arg(&spec.commit_hash);
-        cmd.arg(logfile);
-        cmd.arg(&dev_ctl.serial);
-        let port_numbers = dev_ctl.usb_dev.port_numbers().unwrap();
-        let (last_port, port_prefix) = port_numbers.split_last().unwrap();
-        let last_port_str = format!("{last_port}");
-        let mut port_prefix_str = format!("{}-", dev_ctl.usb_dev.bus_number());
-        for s in port_prefix.iter().map(Some).intersperse(None) {
-            if let Some(s) = s {
-                port_prefix_str.push_str(&format!("{s}"));
-            } else {
-                port_prefix_str.push('.');
-            }
+    let mut phases = Phases::new(spec.id);
+    let mut result = 'run: {
+        // A device that can't be power cycled is in an unknown state, which is no fault of the job
+        if let Some(power_cycle) = &dev_ctl.power_cycle
+            && let Err(e) = power_cycle.run().await
+        {
+            tracing::error!("Failed to power cycle device before job: {e:?}");
+            break 'run JobTermination::device_failed(spec.id, Some(worker_id), format!("failed to power cycle device: {e}"));
         }
-        cmd.arg(last_port_str);
-        cmd.arg(port_prefix_str);
-        cmd.current_dir(std::env::current_dir().unwrap());
-    };
 
-    let result = 'run: {
         let logfile = match async_tempfile::TempFile::new().await {
             Ok(f) => f,
             Err(e) => {
                 tracing::error!("Failed to create temporary log file for job: {e:?}");
-                break 'run JobTermination {
-                    job_id: spec.id,
-                    log: Log {
-                        log: vec![],
-                        truncated: false,
-                    },
-                    result: JobResult::Error,
-                    now: Utc::now(),
-                };
+                break 'run JobTermination::failed(spec.id, Some(worker_id), JobResult::Error);
             }
         };
 
+        let mut results_file = match async_tempfile::TempFile::new().await {
+            Ok(f) => f,
+            Err(e) => {
+                tracing::error!("Failed to create temporary results file for job: {e:?}");
+                break 'run JobTermination::failed(spec.id, Some(worker_id), JobResult::Error);
+            }
+        };
+
+        let phases_file = match async_tempfile::TempFile::new().await {
+            Ok(f) => f,
+            Err(e) => {
+                tracing::error!("Failed to create temporary phases file for job: {e:?}");
+                break 'run JobTermination::failed(spec.id, Some(worker_id), JobResult::Error);
+            }
+        };
+        // a second handle on the phases file, to follow what the script has reached so far
+        match tokio::fs::File::open(phases_file.file_path()).await {
+            Ok(f) => phases.file = Some(f),
+            Err(e) => tracing::warn!("Failed to open phases file, phases of the script won't be reported: {e:?}"),
+        }
+
+        // console capture, started before the script so that it sees everything the device says;
+        // the script talks to the device through it
+
+        let mut console = match &dev_ctl.console {
+            Some(config) => match Console::open(&dev_ctl.serial, config.baud_rate, spec.max_log_bytes as usize) {
+                Ok(console) => Some(console),
+                Err(e) => {
+                    tracing::error!("Failed to open serial console {}: {e:?}", dev_ctl.serial.display());
+                    break 'run JobTermination::device_failed(spec.id, Some(worker_id), format!("failed to open serial console {}: {e}", dev_ctl.serial.display()));
+                }
+            },
+            None => None,
+        };
+        let mut console_open = console.is_some();
+        let idle_timeout = dev_ctl.console.as_ref().and_then(|config| config.idle_timeout);
+        let idle = tokio::time::sleep(idle_timeout.unwrap_or_default());
+        // only once the device has been flashed or has written something, so that building doesn't
+        // count as the device being idle
+        let mut idle_armed = false;
+
         // runner command
 
         let mut cmd = tokio::process::Command::new("bash");
         cmd.arg(test_runner.join(format!("{}.run.sh", spec.job_spec)));
-        setup_args(&mut cmd, logfile.file_path());
+        if let Err(e) = setup_args(
+            &mut cmd,
+            worker_id,
+            &dev_ctl,
+            console.as_ref().map_or(&dev_ctl.serial, |console| console.path()),
+            Some(&spec),
+            logfile.file_path(),
+            Some(results_file.file_path()),
+            Some(phases_file.file_path()),
+        ) {
+            tracing::error!("Failed to set up {}.run.sh process: {e:?}", spec.job_spec);
+            break 'run JobTermination::device_failed(spec.id, Some(worker_id), format!("failed to set up {}.run.sh: {e}", spec.job_spec));
+        }
         tracing::debug!("Running {cmd:?}");
         let mut child = match cmd.spawn() {
             Ok(c) => c,
@@ -75,75 +429,153 @@ pub async fn run_job(
                     "Failed to spawn {}-cleanup.sh process: {e:?}",
                     spec.job_spec
                 );
-                break 'run JobTermination {
-                    job_id: spec.id,
-                    log: Log {
-                        log: vec![],
-                        truncated: false,
-                    },
-                    result: JobResult::Error,
-                    now: Utc::now(),
-                };
+                break 'run JobTermination::failed(spec.id, Some(worker_id), JobResult::Error);
             }
         };
         let pid = child.id();
 
-        let timeout = tokio::time::sleep(Duration::from_secs(120));
+        let timeout = tokio::time::sleep(Duration::from_secs(spec.run_timeout_secs.into()));
+
+        phases.reached(JobPhase::Started, &updates);
 
-        // if let Err(e) = output.try_send(WorkerMsg::Started {
-        //     job_id: spec.id,
-        //     now: Utc::now(),
-        // }) {
-        //     tracing::error!("Worker failed to send job-started message: {e:?}, killing worker");
-        //     return;
-        // }
+        // a second handle on the log file, to stream what the script has written so far
+        let mut log_tail = match tokio::fs::File::open(logfile.file_path()).await {
+            Ok(f) => Some(LogTail {
+                file: f,
+                offset: 0,
+            }),
+            Err(e) => {
+                tracing::warn!("Failed to open log file for streaming, log will only be sent at the end: {e:?}");
+                None
+            }
+        };
+        let mut stream_interval = tokio::time::interval(LOG_STREAM_INTERVAL);
 
         tokio::pin!(timeout);
-        let result = tokio::select! {
-            biased;
-            _ = &mut timeout => {
-                // timed out
-                if let Err(e) = child.kill().await {
-                    tracing::error!("Failed to SIGKILL {}.run.sh process with PID {pid:?}: {e:?}", spec.job_spec);
+        tokio::pin!(idle);
+        let mut cancel_reason = None;
+        let result = loop {
+            tokio::select! {
+                biased;
+                _ = &mut timeout => {
+                    // timed out
+                    if let Err(e) = child.kill().await {
+                        tracing::error!("Failed to SIGKILL {}.run.sh process with PID {pid:?}: {e:?}", spec.job_spec);
+                    }
+                    break JobResult::Timeout;
                 }
-                JobResult::Timeout
-            }
-            _ = &mut cancel => {
-                if let Err(e) = child.kill().await {
-                    tracing::error!("Failed to SIGKILL {}.run.sh process with PID {pid:?}: {e:?}", spec.job_spec);
+                reason = &mut cancel => {
+                    if let Err(e) = child.kill().await {
+                        tracing::error!("Failed to SIGKILL {}.run.sh process with PID {pid:?}: {e:?}", spec.job_spec);
+                    }
+                    cancel_reason = reason.ok().flatten();
+                    break JobResult::Canceled;
                 }
-                JobResult::Canceled
-            }
-            res = child.wait() => {
-                match res {
-                    Ok(exit_status) => {
-                        exit_status.code().map(|code| match code {
-                            0 => JobResult::Correct,
-                            1 => JobResult::Incorrect,
-                            2 => JobResult::Error,
-                            3 => JobResult::Canceled,
-                            4 => JobResult::Timeout,
-                            other => {
-                                tracing::error!("Unrecognized exit code from {}.run.sh: {other}", spec.job_spec);
-                                JobResult::Error
+                _ = &mut idle, if console_open && idle_armed && idle_timeout.is_some() => {
+                    if let Err(e) = child.kill().await {
+                        tracing::error!("Failed to SIGKILL {}.run.sh process with PID {pid:?}: {e:?}", spec.job_spec);
+                    }
+                    if let Some(console) = &mut console {
+                        console.note(&format!(
+                            "no console output for {}s, stopping job",
+                            idle_timeout.unwrap_or_default().as_secs()
+                        ));
+                    }
+                    break JobResult::Timeout;
+                }
+                res = read_console(&mut console), if console_open => {
+                    match res {
+                        Ok(true) => {
+                            idle_armed = true;
+                            if let Some(idle_timeout) = idle_timeout {
+                                idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
                             }
-                        }).unwrap_or_else(|| {
-                            tracing::error!("");
-                            JobResult::Error})
-                    },
-                    Err(e) => {
-                        tracing::error!("Error wait()-ing for {}.run.sh: {e:?}", spec.job_spec);
-                        JobResult::Error
-                    },
+                        }
+                        Ok(false) => {
+                            console_open = false;
+                            if let Some(console) = &mut console {
+                                console.note("serial port hung up");
+                            }
+                        }
+                        Err(e) => {
+                            tracing::warn!("Stopped capturing serial console: {e:?}");
+                            console_open = false;
+                            if let Some(console) = &mut console {
+                                console.note(&format!("failed to read serial port: {e}"));
+                            }
+                        }
+                    }
+                }
+                _ = stream_interval.tick() => {
+                    if let Some(tail) = &mut log_tail
+                        && let Err(e) = tail.stream(&spec, &updates).await
+                    {
+                        tracing::warn!("Stopped streaming log: {e:?}");
+                        log_tail = None;
+                    }
+                    phases.read_file(&updates).await;
+                    if !idle_armed
+                        && phases.has_reached(JobPhase::DeviceFlashed)
+                        && let Some(idle_timeout) = idle_timeout
+                    {
+                        idle_armed = true;
+                        idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+                    }
+                }
+                res = child.wait() => {
+                    break match res {
+                        Ok(exit_status) => {
+                            exit_status.code().map(|code| match code {
+                                0 => JobResult::Correct,
+                                1 => JobResult::Incorrect,
+                                2 => JobResult::Error,
+                                3 => JobResult::Canceled,
+                                4 => JobResult::Timeout,
+                                other => {
+                                    tracing::error!("Unrecognized exit code from {}.run.sh: {other}", spec.job_spec);
+                                    JobResult::Error
+                                }
+                            }).unwrap_or_else(|| {
+                                tracing::error!("");
+                                JobResult::Error})
+                        },
+                        Err(e) => {
+                            tracing::error!("Error wait()-ing for {}.run.sh: {e:?}", spec.job_spec);
+                            JobResult::Error
+                        },
+                    };
                 }
             }
         };
 
-        // cleanup command
+        phases.read_file(&updates).await;
+        phases.reached(JobPhase::TestsDone, &updates);
+
+        // stop capturing, so that the cleanup script has the serial port to itself
+        let console_log = console.map(|console| {
+            let mut v = vec![];
+            console.append_to(&mut v, &dev_ctl.serial);
+            v
+        });
+
+        // cleanup command; if it fails, the device is in an unknown state, which is the device's
+        // fault rather than the job's
 
         let mut cmd = tokio::process::Command::new("bash");
         cmd.arg(test_runner.join(format!("{}.cleanup.sh", spec.job_spec)));
-        setup_args(&mut cmd, logfile.file_path());
+        if let Err(e) = setup_args(
+            &mut cmd,
+            worker_id,
+            &dev_ctl,
+            &dev_ctl.serial,
+            Some(&spec),
+            logfile.file_path(),
+            Some(results_file.file_path()),
+            Some(phases_file.file_path()),
+        ) {
+            tracing::error!("Failed to set up {}.cleanup.sh process: {e:?}, killing worker", spec.job_spec);
+            break 'run JobTermination::device_failed(spec.id, Some(worker_id), format!("failed to set up {}.cleanup.sh: {e}", spec.job_spec));
+        }
         let mut child = match cmd.spawn() {
             Ok(c) => c,
             Err(e) => {
@@ -151,19 +583,11 @@ pub async fn run_job(
                     "Failed to spawn {}.cleanup.sh process: {e:?}, killing worker",
                     spec.job_spec
                 );
-                break 'run JobTermination {
-                    job_id: spec.id,
-                    log: Log {
-                        log: vec![],
-                        truncated: false,
-                    },
-                    result: JobResult::Error,
-                    now: Utc::now(),
-                };
+                break 'run JobTermination::device_failed(spec.id, Some(worker_id), format!("failed to spawn {}.cleanup.sh: {e}", spec.job_spec));
             }
         };
         let pid = child.id();
-        let timeout = tokio::time::sleep(Duration::from_secs(45));
+        let timeout = tokio::time::sleep(Duration::from_secs(spec.cleanup_timeout_secs.into()));
         tokio::pin!(timeout);
         tokio::select! {
             biased;
@@ -171,47 +595,33 @@ pub async fn run_job(
                 if let Err(e) = child.kill().await {
                     tracing::error!("Failed to SIGKILL {}.cleanup.sh process with PID {pid:?}: {e:?}, killing worker", spec.job_spec);
                 }
+                tracing::error!("{}.cleanup.sh process timed out, killing worker", spec.job_spec);
+                break 'run JobTermination::device_failed(spec.id, Some(worker_id), format!("{}.cleanup.sh timed out", spec.job_spec));
             }
             res = child.wait() => {
                 match res {
                     Ok(exit_status) => {
                         if !exit_status.success() {
                             tracing::error!("{}.cleanup.sh process exited unsuccesfully with exit code {exit_status}, killing worker", spec.job_spec);
-                            break 'run JobTermination {
-                                job_id: spec.id,
-                                log: Log {
-                                    log: vec![],
-                                    truncated: false,
-                                },
-                                result: JobResult::Error,
-                                now: Utc::now(),
-                            };
+                            break 'run JobTermination::device_failed(spec.id, Some(worker_id), format!("{}.cleanup.sh failed ({exit_status})", spec.job_spec));
                         }
                     }
                     Err(e) => {
                         tracing::error!("Failed to wait() for {}.cleanup.sh process with PID {pid:?}: {e:?}, killing worker", spec.job_spec);
-                        break 'run JobTermination {
-                            job_id: spec.id,
-                            log: Log {
-                                log: vec![],
-                                truncated: false,
-                            },
-                            result: JobResult::Error,
-                            now: Utc::now(),
-                        };
+                        break 'run JobTermination::device_failed(spec.id, Some(worker_id), format!("failed to wait() for {}.cleanup.sh: {e}", spec.job_spec));
                     }
                 }
             }
         }
+        phases.reached(JobPhase::CleanupDone, &updates);
 
         // read log file
 
-        // 64K is enough for anybody
-        const LOG_LIMIT: usize = 1024 * 64;
+        let log_limit = spec.max_log_bytes as usize;
         let mut v = vec![];
-        let log = match logfile.take(LOG_LIMIT as u64 + 1).read_to_end(&mut v).await {
-            Ok(s) if s > LOG_LIMIT => {
-                v.truncate(LOG_LIMIT);
+        let mut log = match logfile.take(log_limit as u64 + 1).read_to_end(&mut v).await {
+            Ok(s) if s > log_limit => {
+                v.truncate(log_limit);
                 Log {
                     log: v,
                     truncated: true,
@@ -229,15 +639,57 @@ pub async fn run_job(
                 }
             }
         };
+        if let Some(console_log) = console_log {
+            log.log.extend_from_slice(&console_log);
+            if log.log.len() > log_limit {
+                log.log.truncate(log_limit);
+                log.truncated = true;
+            }
+        }
+
+        let test_cases = read_test_cases(&mut results_file).await;
 
         JobTermination {
             job_id: spec.id,
+            device_id: Some(worker_id),
             log,
             result,
+            device_failure: None,
+            test_cases,
+            phases: vec![],
             now: Utc::now(),
+            cancel_reason,
         }
     };
-    if let Err(_) = output.send(result) {
+    result.phases = phases.events;
+    if output.send(result).is_err() {
         tracing::error!("Failed to send job termination: dispatcher channel closed");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn last_line_comes_from_the_end_of_a_long_log() {
+        let mut file = async_tempfile::TempFile::new().await.unwrap();
+        for i in 0..1000 {
+            file.write_all(format!("setting up, step {i}\n").as_bytes()).await.unwrap();
+        }
+        file.write_all(b"serial port vanished\n\n").await.unwrap();
+        file.flush().await.unwrap();
+
+        let mut log = tokio::fs::File::open(file.file_path()).await.unwrap();
+        assert_eq!(last_line(&mut log, 64).await.as_deref(), Some("serial port vanished"));
+    }
+
+    #[tokio::test]
+    async fn empty_log_has_no_last_line() {
+        let file = async_tempfile::TempFile::new().await.unwrap();
+        let mut log = tokio::fs::File::open(file.file_path()).await.unwrap();
+        assert_eq!(last_line(&mut log, 64).await, None);
+    }
+}
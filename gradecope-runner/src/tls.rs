@@ -0,0 +1,58 @@
+//! Mutual TLS with the switchboard.
+
+use std::{path::Path, sync::Arc};
+
+use gradecope_proto::tls::load_certs;
+use rustls::pki_types::{PrivateKeyDer, ServerName, pem::PemObject as _};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+
+/// TLS configuration for connecting to the switchboard: only trusts a switchboard with a
+/// certificate signed by `ca`, and presents `cert` and `key`.
+pub fn client_config(ca: &Path, cert: &Path, key: &Path) -> eyre::Result<Arc<rustls::ClientConfig>> {
+    let mut roots = rustls::RootCertStore::empty();
+    for ca_cert in load_certs(ca)? {
+        roots.add(ca_cert)?;
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| eyre::eyre!("failed to read private key from {}: {e}", key.display()))?;
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_client_auth_cert(load_certs(cert)?, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Opens a TLS connection over `stream` to the switchboard at `addr`, a `host:port` whose host its
+/// certificate must be for.
+pub async fn connect(
+    config: Arc<rustls::ClientConfig>,
+    addr: &str,
+    stream: TcpStream,
+) -> eyre::Result<TlsStream<TcpStream>> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _port)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let server_name = ServerName::try_from(host.to_owned())
+        .map_err(|e| eyre::eyre!("invalid switchboard host {host:?}: {e}"))?;
+    tokio_rustls::TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| eyre::eyre!("TLS handshake with {addr} failed: {e}"))
+}
+
+/// The common name in the subject of the certificate at `path`, which the switchboard knows the
+/// runner by.
+pub fn certificate_name(path: &Path) -> eyre::Result<String> {
+    let cert = load_certs(path)?.swap_remove(0);
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert)
+        .map_err(|e| eyre::eyre!("failed to parse certificate {}: {e}", path.display()))?;
+    let name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .ok_or_else(|| eyre::eyre!("certificate {} has no common name", path.display()))?;
+    Ok(name.to_owned())
+}
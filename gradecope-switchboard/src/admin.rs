@@ -0,0 +1,937 @@
+use std::sync::Arc;
+
+use eyre::OptionExt as _;
+use futures::StreamExt as _;
+use gradecope_proto::{
+    admin::{
+        Admin, DeviceStatus, GradebookRow, JobType, JobTypeConfig, Lab, LateStatus, QueuedJob,
+        RunnerStatus, StudentGrades,
+    },
+    ctl::{CtlError, JobStatus},
+};
+use tarpc::{
+    context,
+    serde_transport::unix,
+    server::{BaseChannel, Channel as _},
+    tokio_serde::formats::Json,
+};
+use chrono::{DateTime, Utc};
+use tokio::net::unix::UCred;
+use users::{get_group_by_name, get_user_by_uid, get_user_groups};
+use uuid::Uuid;
+
+use crate::ctl::to_ctl_error;
+use crate::sql::{JobState, SqlUser};
+use crate::{ServerCtx, grading, scheduler, submission};
+
+async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
+    tokio::spawn(fut);
+}
+
+fn db_error(what: &str) -> impl FnOnce(sqlx::Error) -> eyre::Report {
+    move |e| {
+        tracing::error!("Failed to {what}: {e}");
+        eyre::eyre!(CtlError::InternalError(e.to_string()))
+    }
+}
+
+/// The numeric parts of a [`JobTypeConfig`], as stored in the database.
+struct SqlJobTypeLimits {
+    run_timeout_secs: i32,
+    cleanup_timeout_secs: i32,
+    max_log_bytes: i32,
+}
+impl TryFrom<&JobTypeConfig> for SqlJobTypeLimits {
+    type Error = eyre::Report;
+
+    fn try_from(config: &JobTypeConfig) -> Result<Self, Self::Error> {
+        let convert = |name: &str, value: u32| match i32::try_from(value) {
+            Ok(v) if v > 0 => Ok(v),
+            _ => Err(eyre::eyre!(CtlError::InternalError(format!(
+                "{name} must be between 1 and {max}",
+                max = i32::MAX
+            )))),
+        };
+        Ok(Self {
+            run_timeout_secs: convert("run timeout", config.run_timeout_secs)?,
+            cleanup_timeout_secs: convert("cleanup timeout", config.cleanup_timeout_secs)?,
+            max_log_bytes: convert("max log size", config.max_log_bytes)?,
+        })
+    }
+}
+
+fn check_lab_dates(lab: &Lab) -> eyre::Result<()> {
+    if lab.deadline.is_some_and(|deadline| deadline < lab.released) {
+        eyre::bail!(CtlError::InternalError(
+            "deadline must not be before the release date".to_owned()
+        ));
+    }
+    Ok(())
+}
+
+/// PER CONNECTION state
+#[derive(Clone)]
+struct AdminService {
+    credentials: UCred,
+    server_ctx: Arc<ServerCtx>,
+}
+
+impl AdminService {
+    /// Checks that the peer is in the configured admin group, either as its primary group or as a
+    /// supplementary one.
+    fn check_admin(&self) -> eyre::Result<()> {
+        let admin_group = &self.server_ctx.opts.admin_group;
+        let Some(group) = get_group_by_name(admin_group) else {
+            tracing::error!("Admin group {admin_group:?} does not exist");
+            eyre::bail!(CtlError::PermissionDenied);
+        };
+        if self.credentials.gid() == group.gid() {
+            return Ok(());
+        }
+        let user = get_user_by_uid(self.credentials.uid()).ok_or_eyre("couldn't get ID")?;
+        let is_member = get_user_groups(user.name(), user.primary_group_id())
+            .unwrap_or_default()
+            .iter()
+            .any(|g| g.gid() == group.gid());
+        if !is_member {
+            tracing::warn!(
+                "Rejected admin request from {name:?} (uid {uid})",
+                name = user.name(),
+                uid = self.credentials.uid()
+            );
+            eyre::bail!(CtlError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    async fn job_status(&self, job_id: Uuid) -> eyre::Result<JobStatus> {
+        let row = sqlx::query!(
+            r#"
+            SELECT jobs.id, job_types.spec, jobs.state as "state: JobState"
+            FROM jobs
+            JOIN job_types ON jobs.job_type = job_types.id
+            WHERE jobs.id = $1
+            LIMIT 1;
+            "#,
+            job_id
+        )
+        .fetch_optional(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("fetch job status"))?;
+
+        match row {
+            Some(row) => Ok(JobStatus {
+                job_spec: row.spec,
+                job_id: row.id,
+                result: row.state.into(),
+            }),
+            None => eyre::bail!(CtlError::NotFound(format!("Job {job_id} not found"))),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_user(&self, name: String) -> eyre::Result<Uuid> {
+        self.check_admin()?;
+
+        let user_id = Uuid::from_u128(rand::random());
+        match sqlx::query!(
+            "INSERT INTO users (id, name) VALUES ($1, $2);",
+            user_id,
+            name
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        {
+            Ok(_) => {
+                tracing::info!("Added user {name}#{user_id}");
+            }
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                eyre::bail!(CtlError::InternalError(format!("user {name} already exists")))
+            }
+            Err(e) => return Err(db_error("insert user")(e)),
+        }
+
+        // The user row stays even if this fails, so that the listener can be retried with
+        // `sync_listeners` once whatever was wrong with their home directory is fixed.
+        let user = SqlUser { id: user_id, name };
+        if let Err(e) = submission::spawn_socket_listener(self.server_ctx.clone(), user).await {
+            eyre::bail!(CtlError::InternalError(format!(
+                "user was added, but their submission socket could not be set up: {e}"
+            )));
+        }
+        Ok(user_id)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn remove_user(&self, name: String, purge_jobs: bool) -> eyre::Result<()> {
+        self.check_admin()?;
+
+        let mut tx = self
+            .server_ctx
+            .pool
+            .begin()
+            .await
+            .map_err(db_error("begin transaction"))?;
+
+        if purge_jobs {
+            sqlx::query!(
+                "DELETE FROM jobs WHERE owner IN (SELECT id FROM users WHERE name = $1);",
+                name
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error("delete user jobs"))?;
+        }
+
+        match sqlx::query!("DELETE FROM users WHERE name = $1 RETURNING id;", name)
+            .fetch_optional(&mut *tx)
+            .await
+        {
+            Ok(Some(_)) => (),
+            Ok(None) => eyre::bail!(CtlError::NotFound(format!("User {name} not found"))),
+            Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => {
+                eyre::bail!(CtlError::InternalError(format!(
+                    "user {name} still owns jobs; pass --purge-jobs to delete them"
+                )))
+            }
+            Err(e) => return Err(db_error("delete user")(e)),
+        }
+
+        tx.commit().await.map_err(db_error("commit transaction"))?;
+        self.server_ctx.submit_listeners.remove(&name).await;
+        tracing::info!("Removed user {name}");
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn sync_listeners(&self) -> eyre::Result<u32> {
+        self.check_admin()?;
+
+        let spawned = submission::sync_socket_listeners(self.server_ctx.clone())
+            .await
+            .map_err(|e| eyre::eyre!(CtlError::InternalError(e.to_string())))?;
+        Ok(spawned.try_into().unwrap_or(u32::MAX))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn create_job_type(&self, spec: String, config: JobTypeConfig) -> eyre::Result<Uuid> {
+        self.check_admin()?;
+        let limits = SqlJobTypeLimits::try_from(&config)?;
+
+        let job_type_id = Uuid::from_u128(rand::random());
+        match sqlx::query!(
+            r#"
+            INSERT INTO job_types
+                (id, spec, run_timeout_secs, cleanup_timeout_secs, max_log_bytes, device_class, enabled)
+            VALUES ($1, $2, $3, $4, $5, $6, $7);
+            "#,
+            job_type_id,
+            spec,
+            limits.run_timeout_secs,
+            limits.cleanup_timeout_secs,
+            limits.max_log_bytes,
+            config.device_class,
+            config.enabled,
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        {
+            Ok(_) => {
+                tracing::info!("Created job type {spec}#{job_type_id}");
+                Ok(job_type_id)
+            }
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                eyre::bail!(CtlError::InternalError(format!("job type {spec} already exists")))
+            }
+            Err(e) => Err(db_error("insert job type")(e)),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn configure_job_type(&self, spec: String, config: JobTypeConfig) -> eyre::Result<()> {
+        self.check_admin()?;
+        let limits = SqlJobTypeLimits::try_from(&config)?;
+
+        let updated = sqlx::query!(
+            r#"
+            UPDATE job_types
+            SET run_timeout_secs = $2, cleanup_timeout_secs = $3, max_log_bytes = $4,
+                device_class = $5, enabled = $6
+            WHERE spec = $1;
+            "#,
+            spec,
+            limits.run_timeout_secs,
+            limits.cleanup_timeout_secs,
+            limits.max_log_bytes,
+            config.device_class,
+            config.enabled,
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("update job type"))?;
+
+        if updated.rows_affected() == 0 {
+            eyre::bail!(CtlError::NotFound(format!("Job type {spec} not found")));
+        }
+        tracing::info!("Reconfigured job type {spec}: {config:?}");
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn job_types(&self) -> eyre::Result<Vec<JobType>> {
+        self.check_admin()?;
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT spec, run_timeout_secs, cleanup_timeout_secs, max_log_bytes, device_class, enabled
+            FROM job_types
+            ORDER BY spec;
+            "#
+        )
+        .fetch_all(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("fetch job types"))?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| JobType {
+                spec: row.spec,
+                config: JobTypeConfig {
+                    run_timeout_secs: row.run_timeout_secs.try_into().unwrap_or_default(),
+                    cleanup_timeout_secs: row.cleanup_timeout_secs.try_into().unwrap_or_default(),
+                    max_log_bytes: row.max_log_bytes.try_into().unwrap_or_default(),
+                    device_class: row.device_class,
+                    enabled: row.enabled,
+                },
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn create_lab(&self, lab: Lab) -> eyre::Result<Uuid> {
+        self.check_admin()?;
+        check_lab_dates(&lab)?;
+
+        let lab_id = Uuid::from_u128(rand::random());
+        match sqlx::query!(
+            r#"
+            INSERT INTO labs (id, name, job_type, release_timestamp, deadline_timestamp)
+            SELECT $1, $2, job_types.id, $4, $5
+            FROM job_types
+            WHERE job_types.spec = $3;
+            "#,
+            lab_id,
+            lab.name,
+            lab.job_spec,
+            lab.released.naive_utc(),
+            lab.deadline.map(|t| t.naive_utc()),
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        {
+            Ok(t) if t.rows_affected() == 0 => {
+                eyre::bail!(CtlError::NotFound(format!("Job type {} not found", lab.job_spec)))
+            }
+            Ok(_) => {
+                tracing::info!("Created lab {}#{lab_id}", lab.name);
+                Ok(lab_id)
+            }
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                eyre::bail!(CtlError::InternalError(format!(
+                    "lab {} already exists, or job type {} already belongs to a lab",
+                    lab.name, lab.job_spec
+                )))
+            }
+            Err(e) => Err(db_error("insert lab")(e)),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn configure_lab(&self, lab: Lab) -> eyre::Result<()> {
+        self.check_admin()?;
+        check_lab_dates(&lab)?;
+
+        let Some(job_type_id) = sqlx::query_scalar!(
+            "SELECT id FROM job_types WHERE spec = $1;",
+            lab.job_spec
+        )
+        .fetch_optional(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("fetch job type"))?
+        else {
+            eyre::bail!(CtlError::NotFound(format!("Job type {} not found", lab.job_spec)));
+        };
+
+        let updated = match sqlx::query!(
+            r#"
+            UPDATE labs
+            SET job_type = $2, release_timestamp = $3, deadline_timestamp = $4
+            WHERE name = $1;
+            "#,
+            lab.name,
+            job_type_id,
+            lab.released.naive_utc(),
+            lab.deadline.map(|t| t.naive_utc()),
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        {
+            Ok(t) => t,
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                eyre::bail!(CtlError::InternalError(format!(
+                    "job type {} already belongs to another lab",
+                    lab.job_spec
+                )))
+            }
+            Err(e) => return Err(db_error("update lab")(e)),
+        };
+
+        if updated.rows_affected() == 0 {
+            eyre::bail!(CtlError::NotFound(format!("Lab {} not found", lab.name)));
+        }
+        tracing::info!("Reconfigured lab {}: {lab:?}", lab.name);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn labs(&self) -> eyre::Result<Vec<Lab>> {
+        self.check_admin()?;
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT labs.name, job_types.spec, labs.release_timestamp, labs.deadline_timestamp
+            FROM labs
+            JOIN job_types ON labs.job_type = job_types.id
+            ORDER BY labs.release_timestamp, labs.name;
+            "#
+        )
+        .fetch_all(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("fetch labs"))?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Lab {
+                name: row.name,
+                job_spec: row.spec,
+                released: row.release_timestamp.and_utc(),
+                deadline: row.deadline_timestamp.map(|t| t.and_utc()),
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set_late_day_budget(&self, user: String, days: Option<u32>) -> eyre::Result<()> {
+        self.check_admin()?;
+        let days = days
+            .map(i32::try_from)
+            .transpose()
+            .map_err(|_| eyre::eyre!(CtlError::InternalError("too many late days".to_owned())))?;
+
+        let updated = sqlx::query!(
+            "UPDATE users SET late_day_budget = $2 WHERE name = $1;",
+            user,
+            days
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("update late day budget"))?;
+
+        if updated.rows_affected() == 0 {
+            eyre::bail!(CtlError::NotFound(format!("User {user} not found")));
+        }
+        tracing::info!("Set late day budget of {user} to {days:?}");
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn grant_extension(
+        &self,
+        user: String,
+        lab: String,
+        deadline: DateTime<Utc>,
+    ) -> eyre::Result<()> {
+        self.check_admin()?;
+
+        let granted = sqlx::query!(
+            r#"
+            INSERT INTO extensions (user_id, lab_id, deadline_timestamp)
+            SELECT users.id, labs.id, $3
+            FROM users, labs
+            WHERE users.name = $1 AND labs.name = $2
+            ON CONFLICT (user_id, lab_id) DO UPDATE SET deadline_timestamp = EXCLUDED.deadline_timestamp;
+            "#,
+            user,
+            lab,
+            deadline.naive_utc(),
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("insert extension"))?;
+
+        if granted.rows_affected() == 0 {
+            eyre::bail!(CtlError::NotFound(format!("User {user} or lab {lab} not found")));
+        }
+        tracing::info!("Extended deadline of {lab} for {user} to {deadline}");
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn revoke_extension(&self, user: String, lab: String) -> eyre::Result<()> {
+        self.check_admin()?;
+
+        let revoked = sqlx::query!(
+            r#"
+            DELETE FROM extensions
+            USING users, labs
+            WHERE extensions.user_id = users.id AND extensions.lab_id = labs.id
+                AND users.name = $1 AND labs.name = $2;
+            "#,
+            user,
+            lab,
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("delete extension"))?;
+
+        if revoked.rows_affected() == 0 {
+            eyre::bail!(CtlError::NotFound(format!("No extension of {lab} for {user}")));
+        }
+        tracing::info!("Revoked extension of {lab} for {user}");
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn grades(&self, users: Vec<String>) -> eyre::Result<Vec<StudentGrades>> {
+        self.check_admin()?;
+
+        let filter = (!users.is_empty()).then_some(users.as_slice());
+        let grades = grading::grades(&self.server_ctx, filter)
+            .await
+            .map_err(db_error("compute grades"))?;
+
+        if let Some(missing) = users.iter().find(|name| !grades.iter().any(|g| g.user == **name)) {
+            eyre::bail!(CtlError::NotFound(format!("User {missing} not found")));
+        }
+        Ok(grades
+            .into_iter()
+            .map(|g| StudentGrades {
+                user: g.user,
+                grades: g.grades,
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn gradebook(
+        &self,
+        users: Vec<String>,
+        labs: Vec<String>,
+    ) -> eyre::Result<Vec<GradebookRow>> {
+        let students = self.grades(users).await?;
+
+        let known_labs = sqlx::query_scalar!("SELECT name FROM labs WHERE name = ANY($1);", &labs)
+            .fetch_all(&self.server_ctx.pool)
+            .await
+            .map_err(db_error("fetch labs"))?;
+        if let Some(missing) = labs.iter().find(|name| !known_labs.contains(name)) {
+            eyre::bail!(CtlError::NotFound(format!("Lab {missing} not found")));
+        }
+
+        Ok(students
+            .into_iter()
+            .flat_map(|student| {
+                let user = student.user;
+                student.grades.labs.into_iter().map(move |grade| {
+                    let late_status = match &grade.checkoff {
+                        None => LateStatus::NotCheckedOff,
+                        Some(c) if c.on_time() => LateStatus::OnTime,
+                        Some(c) if c.excused => LateStatus::Excused,
+                        Some(_) => LateStatus::Late,
+                    };
+                    GradebookRow {
+                        user: user.clone(),
+                        lab: grade.lab,
+                        job_spec: grade.job_spec,
+                        best_result: grade.best_result,
+                        first_pass: grade.checkoff.as_ref().map(|c| c.submitted),
+                        attempts: grade.attempts,
+                        days_late: grade.checkoff.as_ref().map_or(0, |c| c.days_late),
+                        late_status,
+                    }
+                })
+            })
+            .filter(|row| labs.is_empty() || labs.contains(&row.lab))
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn runners(&self) -> eyre::Result<Vec<RunnerStatus>> {
+        self.check_admin()?;
+
+        let runners = sqlx::query!(
+            r#"
+            SELECT id, name, version, job_specs, connected, peer_addr, last_seen_timestamp,
+                jobs_run, jobs_errored
+            FROM runners
+            ORDER BY name;
+            "#
+        )
+        .fetch_all(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("fetch runners"))?;
+        let devices = sqlx::query!(
+            r#"
+            SELECT id, runner_id, serial, usb_bus, usb_ports, class, connected, current_job,
+                quarantine_reason, jobs_run, jobs_errored
+            FROM devices
+            ORDER BY usb_bus, usb_ports;
+            "#
+        )
+        .fetch_all(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("fetch devices"))?;
+
+        let draining_runners = self.server_ctx.draining_runners.lock().await.clone();
+
+        let count = |n: i32| u32::try_from(n).unwrap_or_default();
+        Ok(runners
+            .into_iter()
+            .map(|runner| RunnerStatus {
+                devices: devices
+                    .iter()
+                    .filter(|device| device.runner_id == runner.id)
+                    .map(|device| DeviceStatus {
+                        id: device.id,
+                        serial: device.serial.clone(),
+                        usb_bus: device.usb_bus.try_into().unwrap_or_default(),
+                        usb_ports: device
+                            .usb_ports
+                            .iter()
+                            .map(|&port| port.try_into().unwrap_or_default())
+                            .collect(),
+                        class: device.class.clone(),
+                        connected: device.connected,
+                        current_job: device.current_job,
+                        quarantine_reason: device.quarantine_reason.clone(),
+                        jobs_run: count(device.jobs_run),
+                        jobs_errored: count(device.jobs_errored),
+                    })
+                    .collect(),
+                id: runner.id,
+                name: runner.name,
+                version: runner.version,
+                job_specs: runner.job_specs,
+                connected: runner.connected,
+                peer_addr: runner.peer_addr,
+                last_seen: runner.last_seen_timestamp.and_utc(),
+                draining: draining_runners.contains(&runner.id),
+                jobs_run: count(runner.jobs_run),
+                jobs_errored: count(runner.jobs_errored),
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn queue(&self) -> eyre::Result<Vec<QueuedJob>> {
+        self.check_admin()?;
+
+        let mut rows = sqlx::query!(
+            r#"
+            SELECT jobs.id, users.name, job_types.spec, jobs.state as "state: JobState",
+                jobs.submit_timestamp, jobs.start_timestamp
+            FROM jobs
+            JOIN job_types ON jobs.job_type = job_types.id
+            JOIN users ON jobs.owner = users.id
+            WHERE jobs.state IN ('submitted', 'started')
+            ORDER BY jobs.submit_timestamp ASC;
+            "#
+        )
+        .fetch_all(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("fetch job queue"))?;
+
+        // running jobs first, then queued jobs in the order the scheduler will hand them out
+        let order = scheduler::ordered_queue(&self.server_ctx, None, None, None)
+            .await
+            .map_err(db_error("order job queue"))?;
+        rows.sort_by_key(|row| order.iter().position(|id| *id == row.id));
+
+        Ok(rows
+            .into_iter()
+            .map(|row| QueuedJob {
+                job_id: row.id,
+                user: row.name,
+                job_spec: row.spec,
+                result: row.state.into(),
+                submitted: row.submit_timestamp.and_utc(),
+                started: row.start_timestamp.map(|t| t.and_utc()),
+            })
+            .collect())
+    }
+
+    /// Like [`crate::ctl`]'s cancellation, but for any user's job and with a caller-supplied
+    /// reason. A pending request on a running job is overwritten.
+    #[tracing::instrument(skip(self))]
+    async fn force_cancel(&self, job_id: Uuid, reason: String) -> eyre::Result<JobStatus> {
+        self.check_admin()?;
+
+        sqlx::query!(
+            r#"
+            UPDATE jobs
+            SET
+                state = CASE WHEN state = 'submitted' THEN 'canceled' ELSE state END,
+                stop_timestamp = CASE WHEN state = 'submitted' THEN NOW() ELSE stop_timestamp END,
+                cancel_reason = CASE WHEN state = 'submitted' THEN $2 ELSE cancel_reason END,
+                cancel_request = CASE WHEN state = 'started' THEN $2 ELSE cancel_request END
+            WHERE id = $1 AND state IN ('submitted', 'started');
+            "#,
+            job_id,
+            reason
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        .map_err(db_error("cancel job"))?;
+
+        self.job_status(job_id).await
+    }
+
+    /// Resets a job to `submitted`, clearing everything recorded about previous runs. Running jobs
+    /// are refused, since their runner would go on with the old run; they have to be canceled and
+    /// requeued once they stopped.
+    #[tracing::instrument(skip(self))]
+    async fn requeue(&self, job_id: Uuid) -> eyre::Result<JobStatus> {
+        self.check_admin()?;
+
+        let mut tx = self
+            .server_ctx
+            .pool
+            .begin()
+            .await
+            .map_err(db_error("requeue job"))?;
+        let state = sqlx::query_scalar!(
+            r#"SELECT state as "state: JobState" FROM jobs WHERE id = $1 FOR UPDATE;"#,
+            job_id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error("requeue job"))?;
+        match state {
+            None => eyre::bail!(CtlError::NotFound(format!("Job {job_id} not found"))),
+            Some(JobState::Started) => eyre::bail!(CtlError::InternalError(format!(
+                "job {job_id} is running; cancel it, and requeue it once it stopped"
+            ))),
+            Some(JobState::Submitted) => {}
+            Some(_) => {
+                sqlx::query!(
+                    r#"
+                    UPDATE jobs
+                    SET
+                        state = 'submitted',
+                        start_timestamp = NULL,
+                        stop_timestamp = NULL,
+                        run_log = NULL,
+                        live_log = NULL,
+                        cancel_reason = NULL,
+                        cancel_request = NULL,
+                        test_result = NULL,
+                        runner_id = NULL,
+                        error_reason = NULL,
+                        requeue_count = 0
+                    WHERE id = $1;
+                    "#,
+                    job_id
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(db_error("requeue job"))?;
+                sqlx::query!("DELETE FROM test_cases WHERE job_id = $1;", job_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(db_error("requeue job"))?;
+                sqlx::query!("DELETE FROM job_phases WHERE job_id = $1;", job_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(db_error("requeue job"))?;
+            }
+        }
+        tx.commit().await.map_err(db_error("requeue job"))?;
+
+        self.job_status(job_id).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn drain_runner(&self, name: String) -> eyre::Result<()> {
+        self.check_admin()?;
+
+        let runner_id = gradecope_proto::runner::runner_id(&name);
+        // held so that the runner can't disconnect in between, leaving its ID behind
+        let connected_runners = self.server_ctx.connected_runners.lock().await;
+        if !connected_runners.contains(&runner_id) {
+            eyre::bail!(CtlError::NotFound(format!("Runner {name} is not connected")));
+        }
+        self.server_ctx.draining_runners.lock().await.insert(runner_id);
+        drop(connected_runners);
+        tracing::info!("Draining runner {name}");
+        // wakes the runner's wait for work, so that it finds out
+        self.server_ctx.jobs_changed.send_replace(());
+        Ok(())
+    }
+}
+
+impl Admin for AdminService {
+    async fn add_user(self, _: context::Context, name: String) -> Result<Uuid, CtlError> {
+        AdminService::add_user(&self, name).await.map_err(to_ctl_error)
+    }
+    async fn remove_user(
+        self,
+        _: context::Context,
+        name: String,
+        purge_jobs: bool,
+    ) -> Result<(), CtlError> {
+        AdminService::remove_user(&self, name, purge_jobs)
+            .await
+            .map_err(to_ctl_error)
+    }
+    async fn sync_listeners(self, _: context::Context) -> Result<u32, CtlError> {
+        AdminService::sync_listeners(&self).await.map_err(to_ctl_error)
+    }
+    async fn create_job_type(
+        self,
+        _: context::Context,
+        spec: String,
+        config: JobTypeConfig,
+    ) -> Result<Uuid, CtlError> {
+        AdminService::create_job_type(&self, spec, config)
+            .await
+            .map_err(to_ctl_error)
+    }
+    async fn configure_job_type(
+        self,
+        _: context::Context,
+        spec: String,
+        config: JobTypeConfig,
+    ) -> Result<(), CtlError> {
+        AdminService::configure_job_type(&self, spec, config)
+            .await
+            .map_err(to_ctl_error)
+    }
+    async fn job_types(self, _: context::Context) -> Result<Vec<JobType>, CtlError> {
+        AdminService::job_types(&self).await.map_err(to_ctl_error)
+    }
+    async fn create_lab(self, _: context::Context, lab: Lab) -> Result<Uuid, CtlError> {
+        AdminService::create_lab(&self, lab).await.map_err(to_ctl_error)
+    }
+    async fn configure_lab(self, _: context::Context, lab: Lab) -> Result<(), CtlError> {
+        AdminService::configure_lab(&self, lab).await.map_err(to_ctl_error)
+    }
+    async fn labs(self, _: context::Context) -> Result<Vec<Lab>, CtlError> {
+        AdminService::labs(&self).await.map_err(to_ctl_error)
+    }
+    async fn set_late_day_budget(
+        self,
+        _: context::Context,
+        user: String,
+        days: Option<u32>,
+    ) -> Result<(), CtlError> {
+        AdminService::set_late_day_budget(&self, user, days)
+            .await
+            .map_err(to_ctl_error)
+    }
+    async fn grant_extension(
+        self,
+        _: context::Context,
+        user: String,
+        lab: String,
+        deadline: DateTime<Utc>,
+    ) -> Result<(), CtlError> {
+        AdminService::grant_extension(&self, user, lab, deadline)
+            .await
+            .map_err(to_ctl_error)
+    }
+    async fn revoke_extension(
+        self,
+        _: context::Context,
+        user: String,
+        lab: String,
+    ) -> Result<(), CtlError> {
+        AdminService::revoke_extension(&self, user, lab)
+            .await
+            .map_err(to_ctl_error)
+    }
+    async fn grades(
+        self,
+        _: context::Context,
+        users: Vec<String>,
+    ) -> Result<Vec<StudentGrades>, CtlError> {
+        AdminService::grades(&self, users).await.map_err(to_ctl_error)
+    }
+    async fn gradebook(
+        self,
+        _: context::Context,
+        users: Vec<String>,
+        labs: Vec<String>,
+    ) -> Result<Vec<GradebookRow>, CtlError> {
+        AdminService::gradebook(&self, users, labs)
+            .await
+            .map_err(to_ctl_error)
+    }
+    async fn runners(self, _: context::Context) -> Result<Vec<RunnerStatus>, CtlError> {
+        AdminService::runners(&self).await.map_err(to_ctl_error)
+    }
+    async fn queue(self, _: context::Context) -> Result<Vec<QueuedJob>, CtlError> {
+        AdminService::queue(&self).await.map_err(to_ctl_error)
+    }
+    async fn force_cancel(
+        self,
+        _: context::Context,
+        job_id: Uuid,
+        reason: String,
+    ) -> Result<JobStatus, CtlError> {
+        AdminService::force_cancel(&self, job_id, reason)
+            .await
+            .map_err(to_ctl_error)
+    }
+    async fn requeue(self, _: context::Context, job_id: Uuid) -> Result<JobStatus, CtlError> {
+        AdminService::requeue(&self, job_id)
+            .await
+            .map_err(to_ctl_error)
+    }
+    async fn drain_runner(self, _: context::Context, name: String) -> Result<(), CtlError> {
+        AdminService::drain_runner(&self, name)
+            .await
+            .map_err(to_ctl_error)
+    }
+}
+
+pub async fn spawn_socket(server_ctx: Arc<ServerCtx>) -> eyre::Result<()> {
+    let mut incoming = unix::listen(&server_ctx.opts.admin_socket_path, Json::default).await?;
+    tokio::spawn(async move {
+        while let Some(t) = incoming.next().await {
+            let transport = match t {
+                Ok(t) => t,
+                Err(e) => {
+                    tracing::error!("Failed to accept admin connection: {e:?}");
+                    continue;
+                }
+            };
+            let cred = match transport.get_ref().peer_cred() {
+                Ok(cred) => cred,
+                Err(e) => {
+                    tracing::error!("Failed to retrieve peer credentials: {e:?}");
+                    continue;
+                }
+            };
+            let service = AdminService {
+                credentials: cred,
+                server_ctx: Arc::clone(&server_ctx),
+            };
+            let fut = BaseChannel::with_defaults(transport)
+                .execute(service.serve())
+                .for_each(spawn);
+            tokio::spawn(fut);
+        }
+    });
+
+    Ok(())
+}
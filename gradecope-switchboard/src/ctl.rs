@@ -1,7 +1,11 @@
 use std::sync::Arc;
-use crate::{ServerCtx, sql::SqlUser};
-use crate::sql::JobState;
-use gradecope_proto::ctl::{Ctl, CtlError, JobReference, JobResult, JobStatus, Log};
+use crate::{ServerCtx, grading, scheduler, sql::SqlUser, submission};
+use crate::sql::{JobPhase, JobState};
+use crate::submission::SubmitError;
+use gradecope_proto::ctl::{
+    Ctl, CtlError, Grades, JobReference, JobResult, JobStatus, Log, LogTail, PhaseTiming, QueuePosition,
+    TestCase,
+};
 use tarpc::{
     context,
     serde_transport::unix,
@@ -12,11 +16,19 @@ use futures::StreamExt;
 use tokio::net::unix::UCred;
 use users::get_user_by_uid;
 use eyre::OptionExt;
+use uuid::Uuid;
 
 async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
     tokio::spawn(fut);
 }
 
+/// Errors that are already a [`CtlError`] go out as-is, so that e.g. `PermissionDenied` reaches the
+/// client intact; anything else is smuggled out as [`CtlError::InternalError`].
+pub(crate) fn to_ctl_error(e: eyre::Report) -> CtlError {
+    e.downcast::<CtlError>()
+        .unwrap_or_else(|e| CtlError::InternalError(e.to_string()))
+}
+
 impl From<JobState> for JobResult {
     fn from(state: JobState) -> Self {
         match state {
@@ -43,7 +55,7 @@ impl CtlService {
 	let username = user.name().to_str().ok_or_eyre("couldn't convert name to &str")?;
 	match sqlx::query_as!(
 	    SqlUser,
-	    "SELECT * FROM users WHERE name = $1 LIMIT 1;",
+	    "SELECT id, name FROM users WHERE name = $1 LIMIT 1;",
 	    username
 )
 	.fetch_one(&self.server_ctx.pool)
@@ -64,10 +76,21 @@ impl CtlService {
 	eyre::bail!(CtlError::PermissionDenied);
     }
 
+    /// Same as pushing with a `-o <job_spec>` push option, except that the commit must already be
+    /// in the user's repository.
     #[tracing::instrument(skip(self))]
-    async fn accept_submission(&self, commit: String, job_spec: String) -> eyre::Result<()> {
-	tracing::debug!("WIP");
-	eyre::bail!(CtlError::PermissionDenied);
+    async fn accept_submission(&self, commit: String, job_spec: String) -> eyre::Result<Uuid> {
+	let user = self.user().await?;
+
+	let res: Result<Uuid, SubmitError> = try {
+	    let commit = submission::resolve_commit(&self.server_ctx, &user, &commit).await?;
+	    submission::enqueue_job(&self.server_ctx, &user, &commit, &job_spec).await?
+	};
+	match res {
+	    Ok(job_id) => Ok(job_id),
+	    Err(SubmitError::Internal) => eyre::bail!(CtlError::InternalError("internal error".to_owned())),
+	    Err(e) => eyre::bail!(CtlError::InvalidSubmission(e.to_string())),
+	}
     }
 
     #[tracing::instrument(skip(self))]
@@ -112,7 +135,7 @@ impl CtlService {
 
 	let row = sqlx::query!(
 	    r#"
-	    SELECT jobs.run_log
+	    SELECT COALESCE(jobs.run_log, jobs.live_log) as run_log
 	    FROM jobs
 	    JOIN job_types ON jobs.job_type = job_types.id
 	    WHERE jobs.owner = $1 AND jobs.id = $2 AND job_types.spec = $3
@@ -141,6 +164,226 @@ impl CtlService {
 	}
     }
 
+    #[tracing::instrument(skip(self))]
+    async fn get_log_tail(&self, job: JobReference, offset: u64) -> eyre::Result<LogTail> {
+	let user = self.user().await?;
+
+	let row = sqlx::query!(
+	    r#"
+	    SELECT jobs.state as "state: JobState", jobs.run_log, jobs.live_log, jobs.requeue_count
+	    FROM jobs
+	    JOIN job_types ON jobs.job_type = job_types.id
+	    WHERE jobs.owner = $1 AND jobs.id = $2 AND job_types.spec = $3
+	    LIMIT 1;
+	    "#,
+	    user.id,
+	    job.job_id,
+	    job.job_spec
+	)
+	.fetch_optional(&self.server_ctx.pool)
+	.await
+	.map_err(|e| {
+	    tracing::error!("Failed to fetch job log: {e}");
+	    eyre::eyre!(CtlError::InternalError(e.to_string()))
+	})?;
+
+	let Some(row) = row else {
+	    eyre::bail!(CtlError::NotFound(format!(
+		"Job {} with spec {} not found",
+		job.job_id, job.job_spec
+	    )));
+	};
+	let done = !matches!(row.state, JobState::Submitted | JobState::Started);
+	let log = if done { row.run_log } else { row.live_log }.unwrap_or_default();
+	let offset = usize::try_from(offset).unwrap_or(usize::MAX).min(log.len());
+	Ok(LogTail {
+	    data: log[offset..].to_vec(),
+	    done,
+	    attempt: row.requeue_count.try_into().unwrap_or_default(),
+	})
+    }
+
+    /// Fails with `NotFound` unless `job` exists and belongs to `user`.
+    async fn check_job_owner(&self, user: &SqlUser, job: &JobReference) -> eyre::Result<()> {
+	let exists = sqlx::query_scalar!(
+	    r#"
+	    SELECT jobs.id
+	    FROM jobs
+	    JOIN job_types ON jobs.job_type = job_types.id
+	    WHERE jobs.owner = $1 AND jobs.id = $2 AND job_types.spec = $3
+	    LIMIT 1;
+	    "#,
+	    user.id,
+	    job.job_id,
+	    job.job_spec
+	)
+	.fetch_optional(&self.server_ctx.pool)
+	.await
+	.map_err(|e| {
+	    tracing::error!("Failed to fetch job: {e}");
+	    eyre::eyre!(CtlError::InternalError(e.to_string()))
+	})?;
+	if exists.is_none() {
+	    eyre::bail!(CtlError::NotFound(format!(
+		"Job {} with spec {} not found",
+		job.job_id, job.job_spec
+	    )));
+	}
+	Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_test_cases(&self, job: JobReference) -> eyre::Result<Vec<TestCase>> {
+	let user = self.user().await?;
+	self.check_job_owner(&user, &job).await?;
+
+	let rows = sqlx::query!(
+	    r#"
+	    SELECT name, passed, points, max_points, message
+	    FROM test_cases
+	    WHERE job_id = $1
+	    ORDER BY idx ASC;
+	    "#,
+	    job.job_id
+	)
+	.fetch_all(&self.server_ctx.pool)
+	.await
+	.map_err(|e| {
+	    tracing::error!("Failed to fetch test cases: {e}");
+	    eyre::eyre!(CtlError::InternalError(e.to_string()))
+	})?;
+
+	Ok(rows
+	    .into_iter()
+	    .map(|row| TestCase {
+		name: row.name,
+		passed: row.passed,
+		points: row.points,
+		max_points: row.max_points,
+		message: row.message,
+	    })
+	    .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_phases(&self, job: JobReference) -> eyre::Result<Vec<PhaseTiming>> {
+	let user = self.user().await?;
+	self.check_job_owner(&user, &job).await?;
+
+	let rows = sqlx::query!(
+	    r#"
+	    SELECT phase as "phase: JobPhase", timestamp
+	    FROM job_phases
+	    WHERE job_id = $1
+	    ORDER BY timestamp ASC, phase ASC;
+	    "#,
+	    job.job_id
+	)
+	.fetch_all(&self.server_ctx.pool)
+	.await
+	.map_err(|e| {
+	    tracing::error!("Failed to fetch job phases: {e}");
+	    eyre::eyre!(CtlError::InternalError(e.to_string()))
+	})?;
+
+	Ok(rows
+	    .into_iter()
+	    .map(|row| PhaseTiming {
+		phase: row.phase.into(),
+		at: row.timestamp.and_utc(),
+	    })
+	    .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_queue_position(&self, job: JobReference) -> eyre::Result<Option<QueuePosition>> {
+	let user = self.user().await?;
+	self.check_job_owner(&user, &job).await?;
+
+	scheduler::queue_position(&self.server_ctx, job.job_id)
+	    .await
+	    .map_err(|e| {
+		tracing::error!("Failed to compute queue position: {e}");
+		eyre::eyre!(CtlError::InternalError(e.to_string()))
+	    })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_grades(&self) -> eyre::Result<Grades> {
+	let user = self.user().await?;
+
+	let grades = grading::grades(&self.server_ctx, Some(std::slice::from_ref(&user.name)))
+	    .await
+	    .map_err(|e| {
+		tracing::error!("Failed to compute grades: {e}");
+		eyre::eyre!(CtlError::InternalError(e.to_string()))
+	    })?;
+	grades
+	    .into_iter()
+	    .next()
+	    .map(|g| g.grades)
+	    .ok_or_else(|| eyre::eyre!(CtlError::NotFound(user.name)))
+    }
+
+    /// Cancel a job owned by the calling user.
+    ///
+    /// Queued jobs are moved straight to `canceled`. Running jobs only get a cancellation request
+    /// recorded; the runner picks it up through `request_cancellation_notifications`, kills the
+    /// job, and reports it back as canceled along with its log.
+    #[tracing::instrument(skip(self))]
+    async fn cancel_job(&self, job: JobReference) -> eyre::Result<JobStatus> {
+	let user = self.user().await?;
+	const REASON: &str = "canceled by user";
+
+	let queued = sqlx::query!(
+	    r#"
+	    UPDATE jobs
+	    SET state = 'canceled', stop_timestamp = NOW(), cancel_reason = $4
+	    FROM job_types
+	    WHERE jobs.job_type = job_types.id
+		AND jobs.owner = $1 AND jobs.id = $2 AND job_types.spec = $3
+		AND jobs.state = 'submitted'
+	    RETURNING jobs.id;
+	    "#,
+	    user.id,
+	    job.job_id,
+	    job.job_spec,
+	    REASON
+	)
+	.fetch_optional(&self.server_ctx.pool)
+	.await
+	.map_err(|e| {
+	    tracing::error!("Failed to cancel queued job: {e}");
+	    eyre::eyre!(CtlError::InternalError(e.to_string()))
+	})?;
+
+	if queued.is_none() {
+	    sqlx::query!(
+		r#"
+		UPDATE jobs
+		SET cancel_request = $4
+		FROM job_types
+		WHERE jobs.job_type = job_types.id
+		    AND jobs.owner = $1 AND jobs.id = $2 AND job_types.spec = $3
+		    AND jobs.state = 'started' AND jobs.cancel_request IS NULL;
+		"#,
+		user.id,
+		job.job_id,
+		job.job_spec,
+		REASON
+	    )
+	    .execute(&self.server_ctx.pool)
+	    .await
+	    .map_err(|e| {
+		tracing::error!("Failed to request cancellation of running job: {e}");
+		eyre::eyre!(CtlError::InternalError(e.to_string()))
+	    })?;
+	}
+
+	// Jobs that already finished are left alone; either way, report where the job is now.
+	self.get_status(job).await
+    }
+
     #[tracing::instrument(skip(self))]
     async fn get_history(&self, job_spec: Option<String>) -> eyre::Result<Vec<JobStatus>> {
 	let user = self.user().await?;
@@ -217,30 +460,58 @@ impl Ctl for CtlService {
 		"no name".to_owned()
 	    }
 	};
-	return format!("Hello, {}!", name);
+	format!("Hello, {}!", name)
     }
 
-    async fn submit(self, _: context::Context, commit: String, job_spec: String) -> Result<(), CtlError> {
-	return self.accept_submission(commit, job_spec).await
-	    .map_err(|e| CtlError::InternalError(e.to_string()));
+    async fn submit(self, _: context::Context, commit: String, job_spec: String) -> Result<Uuid, CtlError> {
+	self.accept_submission(commit, job_spec)
+	    .await
+	    .map_err(to_ctl_error)
     }
     async fn history(self, _: context::Context, job_spec: Option<String>) -> Result<Vec<JobStatus>, CtlError> {
 	self.get_history(job_spec)
 	    .await
-	    .map_err(|e| CtlError::InternalError(e.to_string()))
+	    .map_err(to_ctl_error)
     }
     async fn status(self, _: context::Context, job: JobReference) -> Result<JobStatus, CtlError> {
 	self.get_status(job)
 	    .await
-	    .map_err(|e| CtlError::InternalError(e.to_string()))
+	    .map_err(to_ctl_error)
     }
     async fn log(self, _: context::Context, job: JobReference) -> Result<Log, CtlError> {
 	self.get_log(job)
 	    .await
-	    .map_err(|e| CtlError::InternalError(e.to_string()))
+	    .map_err(to_ctl_error)
+    }
+    async fn test_cases(self, _: context::Context, job: JobReference) -> Result<Vec<TestCase>, CtlError> {
+	self.get_test_cases(job)
+	    .await
+	    .map_err(to_ctl_error)
+    }
+    async fn phases(self, _: context::Context, job: JobReference) -> Result<Vec<PhaseTiming>, CtlError> {
+	self.get_phases(job)
+	    .await
+	    .map_err(to_ctl_error)
     }
-    async fn cancel(self, _: context::Context, _job: JobReference) -> Result<JobStatus, CtlError> {
-	Err(CtlError::NotImplemented)
+    async fn queue_position(self, _: context::Context, job: JobReference) -> Result<Option<QueuePosition>, CtlError> {
+	self.get_queue_position(job)
+	    .await
+	    .map_err(to_ctl_error)
+    }
+    async fn tail_log(self, _: context::Context, job: JobReference, offset: u64) -> Result<LogTail, CtlError> {
+	self.get_log_tail(job, offset)
+	    .await
+	    .map_err(to_ctl_error)
+    }
+    async fn cancel(self, _: context::Context, job: JobReference) -> Result<JobStatus, CtlError> {
+	self.cancel_job(job)
+	    .await
+	    .map_err(to_ctl_error)
+    }
+    async fn grades(self, _: context::Context) -> Result<Grades, CtlError> {
+	self.get_grades()
+	    .await
+	    .map_err(to_ctl_error)
     }
 
 }
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use gradecope_proto::ctl::{Checkoff, Grades, JobResult, LabGrade, LateDays};
+use uuid::Uuid;
+
+use crate::ServerCtx;
+use crate::sql::JobState;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Like the `From<JobState>` conversion, but tells apart completed jobs that failed their tests.
+pub fn job_result(state: JobState, test_result: Option<&str>) -> JobResult {
+    match (state, test_result) {
+        (JobState::Completed, Some("incorrect")) => JobResult::Incorrect,
+        (state, _) => state.into(),
+    }
+}
+
+/// Whole days from `deadline` to `submitted`, rounded up; 0 if there is no deadline or it was met.
+fn days_late(submitted: NaiveDateTime, deadline: Option<NaiveDateTime>) -> u32 {
+    let Some(deadline) = deadline else {
+        return 0;
+    };
+    let secs = (submitted - deadline).num_seconds();
+    if secs <= 0 {
+        0
+    } else {
+        u32::try_from((secs + SECS_PER_DAY - 1) / SECS_PER_DAY).unwrap_or(u32::MAX)
+    }
+}
+
+/// Spends late days on late checkoffs in deadline order, excusing each one that still fits in the
+/// budget. Returns the number of late days spent.
+fn spend_late_days(labs: &mut [LabGrade], budget: u32) -> u32 {
+    let mut order: Vec<usize> = (0..labs.len()).collect();
+    order.sort_by_key(|&i| labs[i].deadline);
+
+    let mut used = 0;
+    for i in order {
+        if let Some(checkoff) = &mut labs[i].checkoff
+            && !checkoff.excused
+            && used + checkoff.days_late <= budget
+        {
+            checkoff.excused = true;
+            used += checkoff.days_late;
+        }
+    }
+    used
+}
+
+pub struct UserGrades {
+    pub user: String,
+    pub grades: Grades,
+}
+
+/// Computes the standing of users on every released lab, in release order, along with their late
+/// days. If `users` is given, only users with those names are included.
+///
+/// A lab is checked off by the user's earliest passing job, and its lateness is measured against
+/// the user's extension for the lab if they have one, or the lab's deadline otherwise.
+pub async fn grades(
+    server_ctx: &ServerCtx,
+    users: Option<&[String]>,
+) -> sqlx::Result<Vec<UserGrades>> {
+    let pool = &server_ctx.pool;
+    let default_budget = i32::try_from(server_ctx.opts.late_day_budget).unwrap_or(i32::MAX);
+
+    let user_rows = sqlx::query!(
+        r#"
+        SELECT id, name, COALESCE(late_day_budget, $2) as "budget!"
+        FROM users
+        WHERE $1::text[] IS NULL OR name = ANY($1)
+        ORDER BY name;
+        "#,
+        users,
+        default_budget,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // A job counts as the best one for a lab if it passed, then if it may still pass, then by how
+    // close it got; ties go to the most recent submission.
+    let rows = sqlx::query!(
+        r#"
+        SELECT users.id as user_id, labs.name, job_types.spec, labs.deadline_timestamp,
+            extensions.deadline_timestamp as "extended_deadline?",
+            best.state as "best_state?: JobState", best.test_result as "best_test_result?",
+            checkoff.id as "checkoff_id?", checkoff.submit_timestamp as "checkoff_submitted?",
+            (SELECT COUNT(*) FROM jobs
+                WHERE jobs.owner = users.id AND jobs.job_type = labs.job_type) as "attempts!"
+        FROM users
+        CROSS JOIN labs
+        JOIN job_types ON labs.job_type = job_types.id
+        LEFT JOIN extensions ON extensions.user_id = users.id AND extensions.lab_id = labs.id
+        LEFT JOIN LATERAL (
+            SELECT jobs.state, jobs.test_result
+            FROM jobs
+            WHERE jobs.owner = users.id AND jobs.job_type = labs.job_type
+            ORDER BY
+                CASE
+                    WHEN jobs.state = 'completed' AND jobs.test_result = 'correct' THEN 0
+                    WHEN jobs.state = 'started' THEN 1
+                    WHEN jobs.state = 'submitted' THEN 2
+                    WHEN jobs.state = 'completed' THEN 3
+                    WHEN jobs.state IN ('timeout', 'error') THEN 4
+                    ELSE 5
+                END,
+                jobs.submit_timestamp DESC
+            LIMIT 1
+        ) best ON TRUE
+        LEFT JOIN LATERAL (
+            SELECT jobs.id, jobs.submit_timestamp
+            FROM jobs
+            WHERE jobs.owner = users.id AND jobs.job_type = labs.job_type
+                AND jobs.state = 'completed' AND jobs.test_result = 'correct'
+            ORDER BY jobs.submit_timestamp ASC
+            LIMIT 1
+        ) checkoff ON TRUE
+        WHERE labs.release_timestamp <= NOW()::timestamp
+            AND ($1::text[] IS NULL OR users.name = ANY($1))
+        ORDER BY labs.release_timestamp, labs.name;
+        "#,
+        users,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut labs_by_user: HashMap<Uuid, Vec<LabGrade>> = HashMap::new();
+    for row in rows {
+        let deadline = row.extended_deadline.or(row.deadline_timestamp);
+        let checkoff = row
+            .checkoff_id
+            .zip(row.checkoff_submitted)
+            .map(|(job_id, submitted)| {
+                let days_late = days_late(submitted, deadline);
+                Checkoff {
+                    job_id,
+                    submitted: submitted.and_utc(),
+                    days_late,
+                    excused: days_late == 0,
+                }
+            });
+        labs_by_user.entry(row.user_id).or_default().push(LabGrade {
+            lab: row.name,
+            job_spec: row.spec,
+            deadline: deadline.map(|t| t.and_utc()),
+            extended: row.extended_deadline.is_some(),
+            attempts: u32::try_from(row.attempts).unwrap_or(u32::MAX),
+            best_result: row
+                .best_state
+                .map(|state| job_result(state, row.best_test_result.as_deref())),
+            checkoff,
+        });
+    }
+
+    Ok(user_rows
+        .into_iter()
+        .map(|user| {
+            let mut labs = labs_by_user.remove(&user.id).unwrap_or_default();
+            let budget = u32::try_from(user.budget).unwrap_or_default();
+            let used = spend_late_days(&mut labs, budget);
+            UserGrades {
+                user: user.name,
+                grades: Grades {
+                    labs,
+                    late_days: LateDays { budget, used },
+                },
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeDelta};
+
+    use super::*;
+
+    fn at(hours: i64) -> NaiveDateTime {
+        DateTime::UNIX_EPOCH.naive_utc() + TimeDelta::hours(hours)
+    }
+
+    /// A lab due at hour `deadline`, checked off `days_late` days late (or not at all).
+    fn lab(name: &str, deadline: i64, days_late: Option<u32>) -> LabGrade {
+        LabGrade {
+            lab: name.to_owned(),
+            job_spec: name.to_owned(),
+            deadline: Some(at(deadline).and_utc()),
+            extended: false,
+            attempts: 1,
+            best_result: None,
+            checkoff: days_late.map(|days_late| Checkoff {
+                job_id: Uuid::nil(),
+                submitted: at(deadline).and_utc(),
+                days_late,
+                excused: days_late == 0,
+            }),
+        }
+    }
+
+    fn excused(labs: &[LabGrade]) -> Vec<Option<bool>> {
+        labs.iter().map(|lab| lab.checkoff.as_ref().map(|c| c.excused)).collect()
+    }
+
+    #[test]
+    fn days_late_rounds_up_to_whole_days() {
+        assert_eq!(days_late(at(10), None), 0);
+        assert_eq!(days_late(at(10), Some(at(24))), 0);
+        assert_eq!(days_late(at(24), Some(at(24))), 0);
+        assert_eq!(days_late(at(24) + TimeDelta::seconds(1), Some(at(24))), 1);
+        assert_eq!(days_late(at(48), Some(at(24))), 1);
+        assert_eq!(days_late(at(49), Some(at(24))), 2);
+    }
+
+    #[test]
+    fn late_days_are_spent_in_deadline_order() {
+        // listed out of deadline order; the earliest lab gets the budget first
+        let mut labs = vec![lab("b", 48, Some(2)), lab("a", 24, Some(2))];
+        assert_eq!(spend_late_days(&mut labs, 3), 2);
+        assert_eq!(excused(&labs), [Some(false), Some(true)]);
+    }
+
+    #[test]
+    fn labs_that_dont_fit_spend_nothing() {
+        // the 3-day lab doesn't fit in what's left, but the later 1-day lab still does
+        let mut labs = vec![
+            lab("a", 24, Some(2)),
+            lab("b", 48, Some(3)),
+            lab("c", 72, Some(1)),
+            lab("d", 96, None),
+            lab("e", 120, Some(0)),
+        ];
+        assert_eq!(spend_late_days(&mut labs, 4), 3);
+        assert_eq!(excused(&labs), [Some(true), Some(false), Some(true), None, Some(true)]);
+    }
+
+    #[test]
+    fn no_budget_excuses_nothing_late() {
+        let mut labs = vec![lab("a", 24, Some(1)), lab("b", 48, Some(0))];
+        assert_eq!(spend_late_days(&mut labs, 0), 0);
+        assert_eq!(excused(&labs), [Some(false), Some(true)]);
+    }
+}
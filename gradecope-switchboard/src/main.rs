@@ -1,21 +1,37 @@
 #![feature(try_blocks)]
 
 use std::{
+    collections::HashSet,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
 use clap::Parser;
 use sqlx::PgPool;
+use uuid::Uuid;
 
+mod admin;
 mod ctl;
+mod grading;
 mod runner;
+mod scheduler;
 mod sql;
 mod submission;
+mod tls;
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Generate keypairs for mutual TLS between the switchboard and runners, then exit
+    GenCerts(tls::GenCertsOpts),
+}
 
 #[derive(Debug, Parser)]
 pub struct Opts {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     // --- QUOTAS ---
     /// Maximum number of submitted jobs per hour per user
     #[arg(long, default_value_t = 120)]
@@ -24,6 +40,34 @@ pub struct Opts {
     #[arg(long, default_value_t = 4)]
     quota_max_concurrent_jobs: u32,
 
+    // --- SCHEDULING ---
+    /// Policy used to pick which submitted job a runner gets next
+    #[arg(long, value_enum, default_value_t = scheduler::SchedulingPolicy::Fifo)]
+    scheduling_policy: scheduler::SchedulingPolicy,
+    /// Under fair-share scheduling, runner time used this many minutes ago counts half as much
+    /// against a user as runner time used just now
+    #[arg(long, default_value_t = 60)]
+    fair_share_half_life_mins: u32,
+
+    // --- GRADING ---
+    /// Late days each user may spend over all labs, unless set per user with the admin socket
+    #[arg(long, default_value_t = 0)]
+    late_day_budget: u32,
+
+    // --- RECOVERY ---
+    /// Number of times a job is put back in the queue after the runner running it goes away,
+    /// before it is marked as errored instead
+    #[arg(long, default_value_t = 2)]
+    max_job_requeues: u32,
+    /// Seconds the switchboard waits, when told to shut down, for runners to finish and report the
+    /// jobs they are running. A second SIGTERM or SIGINT stops waiting.
+    #[arg(long, default_value_t = 300)]
+    shutdown_timeout_secs: u64,
+    /// Seconds a runner that disconnected while running jobs has to reconnect and report them,
+    /// before the jobs are taken away from it
+    #[arg(long, default_value_t = 60)]
+    runner_reconnect_grace_secs: u64,
+
     // --- PATH CONTROLS ---
     /// Path to the directory where user account home directories are located.
     #[arg(long, default_value = "/home")]
@@ -37,17 +81,46 @@ pub struct Opts {
     /// Path of admin socket
     #[arg(long, default_value = "/var/run/gradecope/gradecope-admin.sock")]
     admin_socket_path: String,
+    /// Unix group whose members may use the admin socket
+    #[arg(long, default_value = "gradecope-admins")]
+    admin_group: String,
     #[arg(long, default_value = "/var/run/gradecope/gradecope-ctl.sock")]
     ctl_socket_path: String,
     // --- RUNNER SERVER CONFIG ---
     /// Address to which the thin WebSocket server for the runner to call home to should be bound.
     #[arg(long, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10121))]
     bind_server: SocketAddr,
+    /// CA certificate that runners' certificates must be signed by. Makes runners connect over
+    /// mutual TLS, and register under the common name in their certificate.
+    #[arg(long, requires_all = ["tls_cert", "tls_key"])]
+    tls_ca: Option<PathBuf>,
+    /// Certificate the switchboard presents to runners, with `--tls-ca`
+    #[arg(long, requires = "tls_ca")]
+    tls_cert: Option<PathBuf>,
+    /// Private key of `--tls-cert`
+    #[arg(long, requires = "tls_ca")]
+    tls_key: Option<PathBuf>,
 }
 
 pub struct ServerCtx {
     opts: Opts,
     pool: PgPool,
+    submit_listeners: submission::SubmissionListenerSet,
+    /// IDs of registered runners that are currently connected
+    connected_runners: tokio::sync::Mutex<HashSet<Uuid>>,
+    /// IDs of connected runners that staff asked to drain; forgotten once they disconnect
+    draining_runners: tokio::sync::Mutex<HashSet<Uuid>>,
+    /// Changed whenever a job is queued or should be canceled (see `scheduler::watch_jobs`), or a
+    /// runner should drain
+    jobs_changed: tokio::sync::watch::Sender<()>,
+    /// Set once the switchboard starts shutting down, after which no jobs are submitted or handed
+    /// out
+    shutting_down: tokio::sync::watch::Sender<bool>,
+}
+impl ServerCtx {
+    pub fn shutting_down(&self) -> bool {
+        *self.shutting_down.borrow()
+    }
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -56,6 +129,13 @@ async fn main() {
 
     let opts = Opts::parse();
 
+    if let Some(Command::GenCerts(gen_certs)) = &opts.command {
+        if let Err(e) = tls::generate(gen_certs) {
+            tracing::error!("Failed to generate keypairs: {e:?}");
+        }
+        return;
+    }
+
     // --- Check that home_prefix is a directory
     if !opts.home_prefix.exists() {
         tracing::error!(
@@ -73,12 +153,15 @@ async fn main() {
     }
 
     // --- Open database connection pool
+    // Timestamps are stored without a time zone, as UTC: both the ones written from here (e.g. lab
+    // deadlines) and the ones Postgres fills in from `NOW()`, which is only UTC if the session is.
     let pool = match sqlx::postgres::PgPoolOptions::new()
         .connect_with(
             std::env::var("DATABASE_URL")
                 .expect("DATABASE_URL must be set")
-                .parse()
-                .expect("invalid DATABASE_URL"),
+                .parse::<sqlx::postgres::PgConnectOptions>()
+                .expect("invalid DATABASE_URL")
+                .options([("TimeZone", "UTC")]),
         )
         .await
     {
@@ -90,7 +173,15 @@ async fn main() {
     };
 
     // --- Create server context
-    let server_ctx = Arc::new(ServerCtx { opts, pool });
+    let server_ctx = Arc::new(ServerCtx {
+        opts,
+        pool,
+        submit_listeners: Default::default(),
+        connected_runners: Default::default(),
+        draining_runners: Default::default(),
+        jobs_changed: tokio::sync::watch::Sender::new(()),
+        shutting_down: tokio::sync::watch::Sender::new(false),
+    });
 
     // there are a few different components we have to handle:
     //  1. submission socket listening
@@ -101,23 +192,30 @@ async fn main() {
     // submission sockets push directly to the database job queue if within quotas
     // runner socket is more complicated
     // student socket is quite simple, just wait for command then dump JSON
-    // admin socket is also simple, if someone writes to the admin socket we just add a submission
-    //      socket listener
+    // admin socket is also simple, adding a user through the admin socket also adds their
+    //      submission socket listener
+
+    // --- Any jobs still marked as started belonged to runners connected to a previous instance,
+    //     which get the grace period to reconnect before the jobs are recovered
+    if let Err(e) = runner::record_disconnection(&server_ctx, None).await {
+        tracing::error!("Failed to mark runners as disconnected: {e:?}");
+        return;
+    }
+    tokio::spawn(runner::recover_orphaned_jobs_after_grace(server_ctx.clone(), None));
+
+    tokio::spawn(scheduler::watch_jobs(server_ctx.clone()));
 
     // --- Start up submission socket listeners for all users currently in the database
-    let submit_listeners = match submission::spawn_socket_listeners(server_ctx.clone()).await {
-        Ok(t) => t,
-        Err(e) => {
-            tracing::error!("Failed to spawn submission socket listeners: {e:?}");
-            return;
-        }
-    };
+    if let Err(e) = submission::spawn_socket_listeners(server_ctx.clone()).await {
+        tracing::error!("Failed to spawn submission socket listeners: {e:?}");
+        return;
+    }
 
     let _runner_handler = match runner::spawn_handler(server_ctx.clone()).await {
         Ok(t) => t,
         Err(e) => {
             tracing::error!("Failed to serve runner control server: {e:?}");
-            submit_listeners.close().await;
+            server_ctx.submit_listeners.close().await;
             return;
         }
     };
@@ -130,9 +228,64 @@ async fn main() {
         }
     };
 
-    loop {}
+    match admin::spawn_socket(server_ctx.clone()).await {
+        Ok(()) => (),
+        Err(e) => {
+            tracing::error!("Failed to spawn admin socket: {e:?}");
+            return;
+        }
+    };
+
+    let mut signals = match Signals::new() {
+        Ok(t) => t,
+        Err(e) => {
+            tracing::error!("Failed to install signal handlers: {e:?}");
+            server_ctx.submit_listeners.close().await;
+            return;
+        }
+    };
+    signals.recv().await;
 
-    // // --- Shut down submission socket listeners
-    // submit_listeners.close().await;
-    // cli_listener.close().await;
+    // --- Stop taking submissions and handing out jobs, and give runners time to report the jobs
+    //     they are running; whatever they don't report is recovered by the next instance
+    tracing::info!("Shutting down; press Ctrl-C again to stop waiting for running jobs");
+    server_ctx.shutting_down.send_replace(true);
+    tokio::select! {
+        () = runner::wait_for_running_jobs(
+            &server_ctx,
+            Duration::from_secs(server_ctx.opts.shutdown_timeout_secs),
+        ) => (),
+        () = signals.recv() => tracing::warn!("Not waiting for running jobs any longer"),
+    }
+
+    // --- Shut down socket listeners
+    server_ctx.submit_listeners.close().await;
+    for socket_path in [&server_ctx.opts.ctl_socket_path, &server_ctx.opts.admin_socket_path] {
+        if let Err(e) = std::fs::remove_file(socket_path) {
+            tracing::warn!("Unable to remove socket at {socket_path}: {e}");
+        }
+    }
+    tracing::info!("Shut down");
+}
+
+/// The signals that make the switchboard shut down.
+struct Signals {
+    sigterm: tokio::signal::unix::Signal,
+    sigint: tokio::signal::unix::Signal,
+}
+impl Signals {
+    fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+        Ok(Self {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.sigterm.recv() => (),
+            _ = self.sigint.recv() => (),
+        }
+    }
 }
@@ -1,6 +1,7 @@
 use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{
+    Extension,
     extract::{
         ConnectInfo, State, WebSocketUpgrade,
         ws::{Message, WebSocket},
@@ -8,52 +9,505 @@ use axum::{
     response::IntoResponse,
 };
 use bytes::{Buf as _, BufMut as _, BytesMut};
+use chrono::NaiveDateTime;
 use futures::{SinkExt, StreamExt as _};
-use gradecope_proto::runner::{JobResponse, JobResult, JobSpec, JobTermination, Switchboard as _};
+use gradecope_proto::runner::{
+    DeviceInfo, JobResponse, JobResult, JobSpec, JobTermination, LogChunk, PhaseEvent,
+    RegistrationResponse, RunnerRegistration, Switchboard as _, WorkNotification,
+};
 use tarpc::{context::Context, server::Channel as _};
-use tokio::task::JoinHandle;
+use tokio::{sync::RwLock, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::sql::{JobPhase, JobState};
+use crate::tls::{self, RunnerCertificate};
+use crate::{Opts, ServerCtx, scheduler};
 
-use crate::ServerCtx;
-use crate::sql::JobState;
+/// How long `wait_for_work` waits for something to happen before returning empty-handed. Runners
+/// give the call a longer deadline than this.
+const WORK_WAIT: Duration = Duration::from_secs(20);
 
 pub struct Handle {
     #[allow(dead_code)]
     join_handle: JoinHandle<eyre::Result<()>>,
 }
 
+/// What the switchboard remembers about a runner once it has registered.
+#[derive(Debug, Clone)]
+struct RegisteredRunner {
+    /// Recorded on every job the runner takes so that the jobs can be recovered if the runner goes
+    /// away.
+    id: Uuid,
+    name: String,
+    job_specs: Vec<String>,
+}
+
 #[derive(Clone)]
 struct SwitchboardServer {
     server_ctx: Arc<ServerCtx>,
+    peer_addr: SocketAddr,
+    /// The name in the runner's certificate, if it connected over mutual TLS
+    certificate_name: Option<String>,
+    /// Shared by all requests on the connection; `None` until the runner registers.
+    runner: Arc<RwLock<Option<RegisteredRunner>>>,
+}
+impl SwitchboardServer {
+    async fn runner(&self) -> Option<RegisteredRunner> {
+        let runner = self.runner.read().await.clone();
+        if runner.is_none() {
+            tracing::warn!("Ignoring request from runner that hasn't registered");
+        }
+        runner
+    }
+
+    async fn is_draining(&self, runner: &RegisteredRunner) -> bool {
+        self.server_ctx.draining_runners.lock().await.contains(&runner.id)
+    }
+}
+
+/// Takes `started` jobs away from their runner: jobs whose cancellation was already requested are
+/// canceled, and other jobs are put back in the queue, unless they have already been requeued
+/// `max_job_requeues` times, in which case they are marked as errored with `why` as the reason.
+///
+/// Only jobs of the given runner are affected, or of every runner if `runner_id` is `None`; and
+/// likewise only the given job, or every job, if `job_id` is `None`. Returns how many jobs were
+/// taken away.
+async fn requeue_started_jobs(
+    server_ctx: &ServerCtx,
+    runner_id: Option<Uuid>,
+    job_id: Option<Uuid>,
+    why: &str,
+) -> eyre::Result<usize> {
+    let mut tx = server_ctx.pool.begin().await?;
+
+    // a started job has a start_timestamp, so a canceled one must also have a run log
+    let canceled = sqlx::query!(
+        r#"UPDATE jobs
+        SET state = 'canceled', stop_timestamp = NOW(), run_log = COALESCE(live_log, ''),
+            live_log = NULL, cancel_reason = cancel_request
+        WHERE state = 'started' AND ($1::uuid IS NULL OR runner_id = $1)
+            AND ($2::uuid IS NULL OR id = $2)
+            AND cancel_request IS NOT NULL
+        RETURNING id;"#,
+        runner_id,
+        job_id,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let errored = sqlx::query!(
+        r#"UPDATE jobs
+        SET state = 'error', stop_timestamp = NOW(), run_log = live_log, live_log = NULL,
+            error_reason = $4 || '; giving up after ' || requeue_count || ' retries'
+        WHERE state = 'started' AND ($1::uuid IS NULL OR runner_id = $1)
+            AND ($2::uuid IS NULL OR id = $2)
+            AND requeue_count >= $3
+        RETURNING id;"#,
+        runner_id,
+        job_id,
+        i32::try_from(server_ctx.opts.max_job_requeues).unwrap_or(i32::MAX),
+        why,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let requeued = sqlx::query!(
+        r#"UPDATE jobs
+        SET state = 'submitted', start_timestamp = NULL, runner_id = NULL, live_log = NULL,
+            requeue_count = requeue_count + 1
+        WHERE state = 'started' AND ($1::uuid IS NULL OR runner_id = $1)
+            AND ($2::uuid IS NULL OR id = $2)
+        RETURNING id;"#,
+        runner_id,
+        job_id,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    // the next run starts over
+    let requeued_ids: Vec<Uuid> = requeued.iter().map(|row| row.id).collect();
+    sqlx::query!("DELETE FROM job_phases WHERE job_id = ANY($1);", &requeued_ids)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    let taken = canceled.len() + errored.len() + requeued.len();
+    for row in canceled {
+        tracing::info!("Job {} ({why}) was being canceled; marked as canceled", row.id);
+    }
+    for row in errored {
+        tracing::warn!("Job {} ({why}) exceeded its requeue limit; marked as errored", row.id);
+    }
+    for row in requeued {
+        tracing::info!("Requeued job {} ({why})", row.id);
+    }
+
+    Ok(taken)
+}
+
+/// Recovers jobs left in `started` by a runner that disconnected, or by every runner if
+/// `runner_id` is `None` (i.e. at startup), unless the runner reconnects within
+/// `runner_reconnect_grace_secs` and so can still report on the jobs itself.
+pub async fn recover_orphaned_jobs_after_grace(server_ctx: Arc<ServerCtx>, runner_id: Option<Uuid>) {
+    tokio::time::sleep(Duration::from_secs(server_ctx.opts.runner_reconnect_grace_secs)).await;
+    let runner_ids = match runner_id {
+        Some(runner_id) => vec![runner_id],
+        None => match sqlx::query_scalar!(
+            r#"SELECT DISTINCT runner_id as "runner_id!" FROM jobs WHERE state = 'started';"#
+        )
+        .fetch_all(&server_ctx.pool)
+        .await
+        {
+            Ok(runner_ids) => runner_ids,
+            Err(e) => {
+                tracing::error!("Failed to find orphaned jobs: {e}");
+                return;
+            }
+        },
+    };
+    for runner_id in runner_ids {
+        // held throughout so that the runner can't register in between
+        let connected_runners = server_ctx.connected_runners.lock().await;
+        if connected_runners.contains(&runner_id) {
+            continue;
+        }
+        if let Err(e) = requeue_started_jobs(
+            &server_ctx,
+            Some(runner_id),
+            None,
+            "runner disconnected while running job",
+        )
+        .await
+        {
+            tracing::error!("Failed to recover jobs from runner {runner_id}: {e:?}");
+        }
+    }
+}
+
+/// Recovers the jobs a runner that just registered took before, but no longer has: it won't ever
+/// report on them, e.g. because it restarted while they ran.
+async fn recover_forgotten_jobs(
+    server_ctx: &ServerCtx,
+    runner_id: Uuid,
+    unfinished_jobs: &[Uuid],
+) -> eyre::Result<()> {
+    let forgotten = sqlx::query_scalar!(
+        "SELECT id FROM jobs WHERE state = 'started' AND runner_id = $1 AND NOT (id = ANY($2));",
+        runner_id,
+        unfinished_jobs,
+    )
+    .fetch_all(&server_ctx.pool)
+    .await?;
+    for job_id in forgotten {
+        requeue_started_jobs(server_ctx, Some(runner_id), Some(job_id), "runner lost track of job")
+            .await?;
+    }
+    Ok(())
+}
+
+/// Counts a job that a runner finished towards its and its device's statistics, and frees the
+/// device.
+async fn record_run(
+    conn: &mut sqlx::PgConnection,
+    runner_id: Uuid,
+    device_id: Option<Uuid>,
+    job_id: Uuid,
+    errored: bool,
+) -> sqlx::Result<()> {
+    let errored = i32::from(errored);
+    sqlx::query!(
+        "UPDATE runners SET jobs_run = jobs_run + 1, jobs_errored = jobs_errored + $2 WHERE id = $1;",
+        runner_id,
+        errored,
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        r#"UPDATE devices
+        SET jobs_run = jobs_run + 1, jobs_errored = jobs_errored + $3,
+            current_job = CASE WHEN current_job = $2 THEN NULL ELSE current_job END
+        WHERE id = $1 AND runner_id = $4;"#,
+        device_id,
+        job_id,
+        errored,
+        runner_id,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Records when a job reached the given phases, and takes its `Started` phase, if given, as its
+/// start time, which until then is when it was handed out.
+async fn record_phases(
+    conn: &mut sqlx::PgConnection,
+    job_id: Uuid,
+    phases: &[PhaseEvent],
+) -> sqlx::Result<()> {
+    let kinds: Vec<JobPhase> = phases.iter().map(|event| event.phase.into()).collect();
+    let timestamps: Vec<NaiveDateTime> = phases.iter().map(|event| event.at.naive_utc()).collect();
+    sqlx::query!(
+        r#"INSERT INTO job_phases (job_id, phase, timestamp)
+        SELECT $1, * FROM UNNEST($2::job_phase[], $3::timestamp[])
+        ON CONFLICT (job_id, phase) DO UPDATE SET timestamp = EXCLUDED.timestamp;"#,
+        job_id,
+        &kinds as &[JobPhase],
+        &timestamps,
+    )
+    .execute(&mut *conn)
+    .await?;
+    if let Some(started) = phases.iter().find(|event| event.phase == gradecope_proto::runner::JobPhase::Started) {
+        sqlx::query!(
+            "UPDATE jobs SET start_timestamp = $2 WHERE id = $1;",
+            job_id,
+            started.at.naive_utc(),
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Records a runner and its devices as connected, adding them to the inventory if they're new.
+/// Devices the runner no longer has are marked as disconnected.
+async fn record_registration(
+    server_ctx: &ServerCtx,
+    runner_id: Uuid,
+    registration: &RunnerRegistration,
+    peer_addr: SocketAddr,
+) -> sqlx::Result<()> {
+    let mut tx = server_ctx.pool.begin().await?;
+
+    sqlx::query!(
+        r#"INSERT INTO runners (id, name, version, job_specs, connected, peer_addr, last_seen_timestamp)
+        VALUES ($1, $2, $3, $4, TRUE, $5, NOW())
+        ON CONFLICT (id) DO UPDATE SET
+            version = EXCLUDED.version, job_specs = EXCLUDED.job_specs, connected = TRUE,
+            peer_addr = EXCLUDED.peer_addr, last_seen_timestamp = EXCLUDED.last_seen_timestamp;"#,
+        runner_id,
+        registration.name,
+        registration.version,
+        &registration.job_specs,
+        peer_addr.to_string(),
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"UPDATE devices SET connected = FALSE, current_job = NULL, quarantine_reason = NULL
+        WHERE runner_id = $1;"#,
+        runner_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    for DeviceInfo { id, serial, usb_bus, usb_ports, class } in &registration.devices {
+        let usb_ports: Vec<i32> = usb_ports.iter().copied().map(i32::from).collect();
+        sqlx::query!(
+            r#"INSERT INTO devices (id, runner_id, serial, usb_bus, usb_ports, class, connected)
+            VALUES ($1, $2, $3, $4, $5, $6, TRUE)
+            ON CONFLICT (id) DO UPDATE SET
+                runner_id = EXCLUDED.runner_id, serial = EXCLUDED.serial, usb_bus = EXCLUDED.usb_bus,
+                usb_ports = EXCLUDED.usb_ports, class = EXCLUDED.class, connected = TRUE;"#,
+            id,
+            runner_id,
+            serial,
+            i32::from(*usb_bus),
+            &usb_ports,
+            class.as_deref(),
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+/// Marks a runner and its devices as disconnected, or every runner if `runner_id` is `None` (i.e.
+/// at startup).
+pub async fn record_disconnection(server_ctx: &ServerCtx, runner_id: Option<Uuid>) -> sqlx::Result<()> {
+    let mut tx = server_ctx.pool.begin().await?;
+    sqlx::query!(
+        "UPDATE runners SET connected = FALSE WHERE $1::uuid IS NULL OR id = $1;",
+        runner_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"UPDATE devices SET connected = FALSE, current_job = NULL
+        WHERE $1::uuid IS NULL OR runner_id = $1;"#,
+        runner_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
 }
 
 impl gradecope_proto::runner::Switchboard for SwitchboardServer {
-    async fn request_job(self, _context: Context) -> JobResponse {
+    async fn register(
+        self,
+        _context: Context,
+        registration: RunnerRegistration,
+    ) -> RegistrationResponse {
+        let mut runner = self.runner.write().await;
+        if runner.is_some() {
+            return RegistrationResponse::Rejected("already registered".to_owned());
+        }
+        if let Some(certificate_name) = &self.certificate_name
+            && *certificate_name != registration.name
+        {
+            tracing::warn!(
+                "Rejected runner {}: its certificate is for runner {certificate_name}",
+                registration.name
+            );
+            return RegistrationResponse::Rejected(format!(
+                "certificate is for a runner named {certificate_name:?}"
+            ));
+        }
+        let id = gradecope_proto::runner::runner_id(&registration.name);
+        if !self.server_ctx.connected_runners.lock().await.insert(id) {
+            tracing::warn!(
+                "Rejected runner {}: a runner with the same ID is already connected",
+                registration.name
+            );
+            return RegistrationResponse::Rejected(format!(
+                "a runner named {:?} is already connected",
+                registration.name
+            ));
+        }
+        // The inventory is informational, so failing to update it doesn't keep the runner out.
+        if let Err(e) = record_registration(&self.server_ctx, id, &registration, self.peer_addr).await {
+            tracing::error!("Failed to record registration of runner {}: {e}", registration.name);
+        }
+        if let Err(e) = recover_forgotten_jobs(&self.server_ctx, id, &registration.unfinished_jobs).await {
+            tracing::error!("Failed to recover jobs forgotten by runner {}: {e:?}", registration.name);
+        }
+        let RunnerRegistration { name, version, devices, job_specs, unfinished_jobs } = registration;
+        tracing::info!(
+            "Registered runner {name}#{id} (version {version}) with {} device(s), running {job_specs:?}",
+            devices.len()
+        );
+        if !unfinished_jobs.is_empty() {
+            tracing::info!("Runner {name} resumed with unfinished jobs {unfinished_jobs:?}");
+        }
+        for device in &devices {
+            tracing::debug!("Runner {name} has device {device:?}");
+        }
+        *runner = Some(RegisteredRunner { id, name, job_specs });
+        RegistrationResponse::Accepted
+    }
+
+    async fn request_job(
+        self,
+        _context: Context,
+        device_classes: Vec<String>,
+        currently_running: Vec<Uuid>,
+    ) -> JobResponse {
+        let Some(runner) = self.runner().await else {
+            return JobResponse::Unavailable;
+        };
+        if self.server_ctx.shutting_down() || self.is_draining(&runner).await {
+            return JobResponse::Unavailable;
+        }
+        // Another runner can claim the picked job before we do, in which case we pick again.
+        const CLAIM_ATTEMPTS: usize = 4;
+        for _ in 0..CLAIM_ATTEMPTS {
+            let next_job =
+                scheduler::next_job(&self.server_ctx, &device_classes, &runner.job_specs, &currently_running);
+            let job_id = match next_job.await {
+                Ok(Some(job_id)) => job_id,
+                Ok(None) => return JobResponse::Unavailable,
+                Err(e) => {
+                    tracing::error!("Failed to pick next job: {e}");
+                    return JobResponse::Unavailable;
+                }
+            };
+            match sqlx::query!(
+                r#"
+                    WITH found AS (UPDATE jobs SET state = 'started', start_timestamp = NOW(), runner_id = $1
+                    WHERE id = $2 AND state = 'submitted'
+                    RETURNING id, owner, job_type, commit)
+                    SELECT found.id, users.name, job_types.spec, found.commit,
+                        job_types.run_timeout_secs, job_types.cleanup_timeout_secs,
+                        job_types.max_log_bytes, job_types.device_class
+                    FROM job_types
+                        INNER JOIN found ON found.job_type = job_types.id
+                        INNER JOIN users ON users.id = found.owner
+                    LIMIT 1
+                    ;
+                   "#,
+                runner.id,
+                job_id,
+            ).fetch_optional(&self.server_ctx.pool)
+                .await {
+                Ok(Some(t)) => {
+                    return JobResponse::Job(JobSpec {
+                        id: t.id,
+                        repo_path: self.server_ctx.opts.home_prefix.join(t.name).join(&self.server_ctx.opts.repo_path).to_string_lossy().to_string(),
+                        commit_hash: t.commit,
+                        job_spec: t.spec,
+                        run_timeout_secs: t.run_timeout_secs.try_into().unwrap_or_default(),
+                        cleanup_timeout_secs: t.cleanup_timeout_secs.try_into().unwrap_or_default(),
+                        max_log_bytes: t.max_log_bytes.try_into().unwrap_or_default(),
+                        device_class: t.device_class,
+                    });
+                },
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::error!("Failed to dequeue job: {e}");
+                    return JobResponse::Unavailable;
+                }
+            }
+        }
+        JobResponse::Unavailable
+    }
+
+    async fn job_started(self, _context: Context, job_id: Uuid, device_id: Uuid) {
+        let Some(runner) = self.runner().await else {
+            return;
+        };
         match sqlx::query!(
-            r#"
-                WITH found AS (UPDATE jobs SET state = 'started', start_timestamp = NOW()
-                WHERE id IN (SELECT id FROM jobs WHERE state = 'submitted' ORDER BY submit_timestamp ASC LIMIT 1)
-                RETURNING id, owner, job_type, commit)
-                SELECT found.id, users.name, job_types.spec, found.commit
-                FROM job_types
-                    INNER JOIN found ON found.job_type = job_types.id
-                    INNER JOIN users ON users.id = found.owner
-                LIMIT 1
-                ;
-               "#,
-        ).fetch_optional(&self.server_ctx.pool)
-            .await {
-            Ok(Some(t)) => {
-                JobResponse::Job(JobSpec {
-                    id: t.id,
-                    repo_path: self.server_ctx.opts.home_prefix.join(t.name).join(&self.server_ctx.opts.repo_path).to_string_lossy().to_string(),
-                    commit_hash: t.commit,
-                    job_spec: t.spec,
-                })
-            },
-            Ok(None) => JobResponse::Unavailable,
-            Err(_) => {
-                todo!()
+            r#"UPDATE devices SET current_job = $2
+            WHERE id = $1 AND runner_id = $3 AND connected
+                AND EXISTS (SELECT 1 FROM jobs WHERE id = $2 AND state = 'started' AND runner_id = $3);"#,
+            device_id,
+            job_id,
+            runner.id,
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        {
+            Ok(t) if t.rows_affected() == 0 => {
+                tracing::warn!("Runner {} started job {job_id} on unknown device {device_id}", runner.name);
             }
+            Ok(_) => (),
+            Err(e) => tracing::error!("Failed to record device of job {job_id}: {e}"),
+        }
+    }
+
+    async fn device_health_changed(
+        self,
+        _context: Context,
+        device_id: Uuid,
+        quarantine_reason: Option<String>,
+    ) {
+        let Some(runner) = self.runner().await else {
+            return;
+        };
+        match &quarantine_reason {
+            Some(reason) => tracing::warn!("Runner {} quarantined device {device_id}: {reason}", runner.name),
+            None => tracing::info!("Runner {} returned device {device_id} to service", runner.name),
+        }
+        if let Err(e) = sqlx::query!(
+            "UPDATE devices SET quarantine_reason = $2 WHERE id = $1 AND runner_id = $3;",
+            device_id,
+            quarantine_reason,
+            runner.id,
+        )
+        .execute(&self.server_ctx.pool)
+        .await
+        {
+            tracing::error!("Failed to record health of device {device_id}: {e}");
         }
     }
 
@@ -63,37 +517,189 @@ impl gradecope_proto::runner::Switchboard for SwitchboardServer {
         termination: JobTermination,
     ) -> () {
         tracing::info!("received termination: {termination:?}");
-        let JobTermination { job_id, log, result, now: _ } = termination;
+        let Some(runner) = self.runner().await else {
+            return;
+        };
+        let JobTermination {
+            job_id,
+            device_id,
+            log,
+            result,
+            device_failure,
+            test_cases,
+            phases,
+            now,
+            cancel_reason,
+        } = termination;
         let new_state = match result {
             JobResult::Correct | JobResult::Incorrect => JobState::Completed,
             JobResult::Error => JobState::Error,
             JobResult::Canceled => JobState::Canceled,
             JobResult::Timeout => JobState::Timeout,
+            JobResult::DeviceFailure => {
+                // The student's code didn't get a fair run, so the job goes back in the queue
+                // instead of being charged to the student. Only the run the job was still
+                // started on counts against the device, not late or repeated reports.
+                let why = match &device_failure {
+                    Some(reason) => format!("device failed while running job: {reason}"),
+                    None => "device failed while running job".to_owned(),
+                };
+                match requeue_started_jobs(&self.server_ctx, Some(runner.id), Some(job_id), &why)
+                .await
+                {
+                    Ok(0) => {}
+                    Ok(_) => {
+                        let r: sqlx::Result<()> = try {
+                            let mut tx = self.server_ctx.pool.begin().await?;
+                            record_run(&mut tx, runner.id, device_id, job_id, true).await?;
+                            tx.commit().await?;
+                        };
+                        if let Err(e) = r {
+                            tracing::error!("Failed to record run of job {job_id}: {e}");
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to requeue job {job_id} after device failure: {e}");
+                    }
+                }
+                return;
+            }
         };
         let test_result = match result {
             JobResult::Correct => Some("correct"),
             JobResult::Incorrect => Some("incorrect"),
             _ => None
         };
+        let errored = matches!(result, JobResult::Error);
 
-        if let Err(e) = sqlx::query!(
+        let r: sqlx::Result<()> = try {
+            let mut tx = self.server_ctx.pool.begin().await?;
+            // A job that stopped as canceled must carry a reason: prefer the one recorded when the
+            // cancellation was requested, since the runner doesn't know why it was told to stop,
+            // and otherwise take the runner's.
+            //
+            // Start and stop times both come from the runner's clock, so that the run time is
+            // right even if it isn't quite in sync with ours; a job the runner never reported as
+            // started didn't run at all.
+            let updated = sqlx::query!(
+                r#"UPDATE jobs
+                SET
+                    state = $2,
+                    start_timestamp = COALESCE(
+                        (SELECT timestamp FROM job_phases WHERE job_id = $1 AND phase = 'started'),
+                        $7),
+                    stop_timestamp = $7,
+                    run_log = $3,
+                    live_log = NULL,
+                    test_result = $4,
+                    cancel_reason = CASE
+                        WHEN $2 = 'canceled'::job_state
+                            THEN COALESCE(cancel_request, $6, 'canceled by runner')
+                        ELSE NULL
+                    END
+                WHERE jobs.id = $1 AND jobs.state = 'started' AND jobs.runner_id = $5;
+                ;"#,
+                job_id,
+                new_state as JobState,
+                log.log, // run log
+                test_result, // test result
+                runner.id,
+                cancel_reason,
+                now.naive_utc(),
+            )
+                .execute(&mut *tx)
+                .await?;
+            if updated.rows_affected() > 0 {
+                record_run(&mut tx, runner.id, device_id, job_id, errored).await?;
+                record_phases(&mut tx, job_id, &phases).await?;
+            }
+            // Only record test cases for the run that actually owned the job
+            if updated.rows_affected() > 0 && !test_cases.is_empty() {
+                let idxs: Vec<i32> = (0..test_cases.len() as i32).collect();
+                let names: Vec<String> = test_cases.iter().map(|t| t.name.clone()).collect();
+                let passed: Vec<bool> = test_cases.iter().map(|t| t.passed).collect();
+                let points: Vec<Option<f64>> = test_cases.iter().map(|t| t.points).collect();
+                let max_points: Vec<Option<f64>> = test_cases.iter().map(|t| t.max_points).collect();
+                let messages: Vec<Option<String>> = test_cases.iter().map(|t| t.message.clone()).collect();
+                sqlx::query!(
+                    r#"INSERT INTO test_cases (job_id, idx, name, passed, points, max_points, message)
+                    SELECT $1, * FROM UNNEST($2::int4[], $3::text[], $4::bool[], $5::float8[], $6::float8[], $7::text[]);"#,
+                    job_id,
+                    &idxs,
+                    &names,
+                    &passed,
+                    &points as &[Option<f64>],
+                    &max_points as &[Option<f64>],
+                    &messages as &[Option<String>],
+                )
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        };
+        if let Err(e) = r {
+            tracing::error!("Failed to update job state for {job_id}: {e}");
+        }
+    }
+
+    async fn append_log(self, _context: Context, chunk: LogChunk) {
+        let Some(runner) = self.runner().await else {
+            return;
+        };
+        let LogChunk { job_id, offset, data } = chunk;
+        let Ok(offset) = i32::try_from(offset) else {
+            tracing::warn!("Dropping log chunk for {job_id} at absurd offset {offset}");
+            return;
+        };
+        match sqlx::query!(
             r#"UPDATE jobs
-            SET
-                state = $2,
-                stop_timestamp = NOW(),
-                run_log = $3,
-                test_result = $4
-            WHERE jobs.id = $1;
-            ;"#,
+            SET live_log = COALESCE(live_log, ''::bytea) || $3
+            WHERE id = $1 AND state = 'started' AND runner_id = $4
+                AND COALESCE(length(live_log), 0) = $2;"#,
             job_id,
-            new_state as JobState,
-            log.log, // run log
-            test_result, // test result
+            offset,
+            data,
+            runner.id,
         )
-            .execute(&self.server_ctx.pool)
-            .await {
-            tracing::error!("Failed to update job state for {job_id}: {e}");
+        .execute(&self.server_ctx.pool)
+        .await
+        {
+            Ok(t) if t.rows_affected() == 0 => {
+                tracing::debug!("Dropped log chunk for {job_id} at offset {offset}");
+            }
+            Ok(_) => (),
+            Err(e) => tracing::error!("Failed to append to log of {job_id}: {e}"),
+        }
+    }
+
+    async fn job_phase(self, _context: Context, event: PhaseEvent) {
+        let Some(runner) = self.runner().await else {
             return;
+        };
+        let job_id = event.job_id;
+        let r: sqlx::Result<bool> = try {
+            let mut tx = self.server_ctx.pool.begin().await?;
+            // Only the run the job is started on gets to time it
+            let running = sqlx::query_scalar!(
+                r#"SELECT EXISTS(
+                    SELECT 1 FROM jobs WHERE id = $1 AND state = 'started' AND runner_id = $2
+                    FOR UPDATE
+                ) as "running!";"#,
+                job_id,
+                runner.id,
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            if running {
+                record_phases(&mut tx, job_id, std::slice::from_ref(&event)).await?;
+            }
+            tx.commit().await?;
+            running
+        };
+        match r {
+            Ok(true) => tracing::debug!("Job {job_id} reached phase {:?}", event.phase),
+            Ok(false) => tracing::debug!("Dropped phase {:?} of job {job_id}, which isn't running", event.phase),
+            Err(e) => tracing::error!("Failed to record phase of {job_id}: {e}"),
         }
     }
 
@@ -103,18 +709,136 @@ impl gradecope_proto::runner::Switchboard for SwitchboardServer {
         currently_running: Vec<uuid::Uuid>,
     ) -> Vec<uuid::Uuid> {
         tracing::trace!("received cancellation request: {currently_running:?}");
-        match sqlx::query!(
-            r#"SELECT id FROM jobs WHERE id = ANY($1::uuid[]) AND state IN ('canceled', 'timeout', 'error', 'completed');"#,
-            &currently_running
-        ).fetch_all(&self.server_ctx.pool)
-            .await {
-            Ok(v) => {
-                v.into_iter().map(|row| row.id).collect()
-            },
-            Err(e) => {
-                tracing::error!("Failed to get terminated job subset of {currently_running:?}: {e}");
-                vec![]
-            },
+        let Some(runner) = self.runner().await else {
+            return vec![];
+        };
+        cancellations(&self.server_ctx, runner.id, &currently_running).await
+    }
+
+    async fn wait_for_work(
+        self,
+        _context: Context,
+        idle_device_classes: Option<Vec<String>>,
+        currently_running: Vec<Uuid>,
+    ) -> WorkNotification {
+        let Some(runner) = self.runner().await else {
+            return WorkNotification::default();
+        };
+        // Every notification says whether the runner was asked to drain, but a runner without idle
+        // devices is only woken up for it if it was asked during this call; it has most likely been
+        // told already otherwise, and would just call again straight away.
+        let drain_requested_before = self.is_draining(&runner).await;
+        // subscribed before looking, so that no change after looking is missed
+        let mut changes = self.server_ctx.jobs_changed.subscribe();
+        let mut shutting_down = self.server_ctx.shutting_down.subscribe();
+        let timeout = tokio::time::sleep(WORK_WAIT);
+        tokio::pin!(timeout);
+        loop {
+            changes.borrow_and_update();
+            // A runner that is still waiting for jobs hasn't been told yet
+            let draining = *shutting_down.borrow_and_update() && idle_device_classes.is_some();
+            let drain_requested = self.is_draining(&runner).await;
+            let jobs_available = match &idle_device_classes {
+                Some(_) if draining || drain_requested => false,
+                Some(device_classes) => {
+                    let next_job = scheduler::next_job(
+                        &self.server_ctx,
+                        device_classes,
+                        &runner.job_specs,
+                        &currently_running,
+                    );
+                    match next_job.await {
+                        Ok(job_id) => job_id.is_some(),
+                        Err(e) => {
+                            tracing::error!("Failed to look for jobs for runner {}: {e}", runner.name);
+                            false
+                        }
+                    }
+                }
+                None => false,
+            };
+            let cancellations = cancellations(&self.server_ctx, runner.id, &currently_running).await;
+            let wake_for_drain = drain_requested && (idle_device_classes.is_some() || !drain_requested_before);
+            if jobs_available || !cancellations.is_empty() || draining || wake_for_drain {
+                return WorkNotification {
+                    jobs_available,
+                    cancellations,
+                    draining,
+                    drain_requested,
+                };
+            }
+            tokio::select! {
+                res = changes.changed() => {
+                    if res.is_err() {
+                        return WorkNotification::default();
+                    }
+                }
+                res = shutting_down.changed() => {
+                    if res.is_err() {
+                        return WorkNotification::default();
+                    }
+                }
+                _ = &mut timeout => return WorkNotification { drain_requested, ..Default::default() },
+            }
+        }
+    }
+
+    async fn drain_requested(self, _context: Context) -> bool {
+        match self.runner().await {
+            Some(runner) => self.is_draining(&runner).await,
+            None => false,
+        }
+    }
+}
+
+/// Waits until connected runners have reported every job they are running, or for `timeout`,
+/// whichever comes first.
+pub async fn wait_for_running_jobs(server_ctx: &ServerCtx, timeout: Duration) {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let runner_ids: Vec<Uuid> = server_ctx.connected_runners.lock().await.iter().copied().collect();
+        match sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM jobs WHERE state = 'started' AND runner_id = ANY($1);"#,
+            &runner_ids,
+        )
+        .fetch_one(&server_ctx.pool)
+        .await
+        {
+            Ok(0) => return,
+            Ok(running) => tracing::info!("Waiting for {running} running job(s) to be reported"),
+            Err(e) => tracing::error!("Failed to count running jobs: {e}"),
+        }
+        if tokio::time::Instant::now() >= deadline {
+            tracing::warn!("Gave up waiting for running jobs to be reported");
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// The subset of `currently_running` that a runner should stop: all but the jobs that are still
+/// started on it and whose cancellation wasn't requested. That includes jobs that already stopped
+/// as far as the switchboard is concerned, and jobs that were queued again or handed to another
+/// runner while it was away.
+async fn cancellations(server_ctx: &ServerCtx, runner_id: Uuid, currently_running: &[Uuid]) -> Vec<Uuid> {
+    match sqlx::query_scalar!(
+        r#"SELECT running.id as "id!" FROM UNNEST($1::uuid[]) AS running(id)
+        WHERE NOT EXISTS (
+            SELECT 1 FROM jobs
+            WHERE jobs.id = running.id
+                AND jobs.state = 'started'
+                AND jobs.runner_id = $2
+                AND jobs.cancel_request IS NULL);"#,
+        currently_running,
+        runner_id,
+    )
+    .fetch_all(&server_ctx.pool)
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("Failed to get terminated job subset of {currently_running:?}: {e}");
+            vec![]
         }
     }
 }
@@ -122,10 +846,21 @@ impl gradecope_proto::runner::Switchboard for SwitchboardServer {
 /// Handles a websocket connection, including Ping/Pong heartbeats, and de/serializes messages for
 /// a [`SwitchboardServer`] constructed from `server_ctx`.
 #[tracing::instrument(skip(server_ctx, ws))]
-async fn connected_runner(peer_addr: SocketAddr, server_ctx: Arc<ServerCtx>, mut ws: WebSocket) {
+async fn connected_runner(
+    peer_addr: SocketAddr,
+    certificate_name: Option<String>,
+    server_ctx: Arc<ServerCtx>,
+    mut ws: WebSocket,
+) {
     tracing::info!("Runner connected from {peer_addr}");
 
-    let switchboard_server = SwitchboardServer { server_ctx };
+    let runner = Arc::new(RwLock::new(None));
+    let switchboard_server = SwitchboardServer {
+        server_ctx: server_ctx.clone(),
+        peer_addr,
+        certificate_name,
+        runner: runner.clone(),
+    };
 
     let (mut client_channel, server_channel) = tarpc::transport::channel::bounded(16);
 
@@ -218,6 +953,17 @@ async fn connected_runner(peer_addr: SocketAddr, server_ctx: Arc<ServerCtx>, mut
                                 } else if pong_idx == ping_idx {
                                     ping_idx += 1;
                                     tries = 0;
+                                    let runner_id = runner.read().await.as_ref().map(|r| r.id);
+                                    if let Some(runner_id) = runner_id
+                                        && let Err(e) = sqlx::query!(
+                                            "UPDATE runners SET last_seen_timestamp = NOW() WHERE id = $1;",
+                                            runner_id
+                                        )
+                                        .execute(&server_ctx.pool)
+                                        .await
+                                    {
+                                        tracing::error!("Failed to record heartbeat of runner {runner_id}: {e}");
+                                    }
                                 } else {
                                     tracing::warn!("Invalid websocket Pong index: {pong_idx} > {ping_idx}");
                                 }
@@ -242,41 +988,69 @@ async fn connected_runner(peer_addr: SocketAddr, server_ctx: Arc<ServerCtx>, mut
     }
 
     let _ = ws.close().await;
+    // the tarpc server only finishes once its transport is closed
+    drop(client_channel);
     if let Err(e) = jh.await {
         tracing::error!("Join error waiting for tarpc server: {e:?}")
     }
+
+    let Some(runner) = runner.write().await.take() else {
+        tracing::info!("Runner at {peer_addr} disconnected without registering");
+        return;
+    };
+    tracing::info!("Runner {}#{} disconnected", runner.name, runner.id);
+    // The ID is only released once the inventory says the runner is gone; otherwise a runner that
+    // reconnects right away could register in between, and then be marked as disconnected.
+    let mut connected_runners = server_ctx.connected_runners.lock().await;
+    if let Err(e) = record_disconnection(&server_ctx, Some(runner.id)).await {
+        tracing::error!("Failed to record disconnection of runner {}: {e}", runner.name);
+    }
+    server_ctx.draining_runners.lock().await.remove(&runner.id);
+    connected_runners.remove(&runner.id);
+    drop(connected_runners);
+    tokio::spawn(recover_orphaned_jobs_after_grace(server_ctx, Some(runner.id)));
 }
 
 /// Upgrades a request to /runner/control to a websocket, and passes the websocket to
 /// [`connected_runner`] if the upgrade was successful.
 async fn websocket_route(
     ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
+    certificate: Option<Extension<RunnerCertificate>>,
     State(state): State<Arc<ServerCtx>>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
+    let certificate_name = certificate.map(|Extension(RunnerCertificate(name))| name);
     ws.on_failed_upgrade(move |e| {
         tracing::error!("Failed websocket upgrade on request from {peer_addr}: {e:?}");
     })
-    .on_upgrade(move |ws| connected_runner(peer_addr, state, ws))
+    .on_upgrade(move |ws| connected_runner(peer_addr, certificate_name, state, ws))
 }
 
 /// Spawns an Axum server that serves a single /runner/control route.
 ///
-/// This runs unauthenticated HTTP, and MUST NOT exposed to the internet; a reverse proxy mTLS
-/// termination MUST be used for production deployments.
+/// Without `--tls-ca`, this runs unauthenticated HTTP, and MUST NOT be exposed to the internet.
 pub async fn spawn_handler(server_ctx: Arc<ServerCtx>) -> eyre::Result<Handle> {
     let listener = tokio::net::TcpListener::bind(&server_ctx.opts.bind_server).await?;
+    let acceptor = match &server_ctx.opts {
+        Opts { tls_ca: Some(ca), tls_cert: Some(cert), tls_key: Some(key), .. } => {
+            Some(tls::acceptor(ca, cert, key)?)
+        }
+        _ => None,
+    };
     let router: axum::Router = axum::Router::new()
         .route("/runner/control", axum::routing::get(websocket_route))
         .with_state(server_ctx);
-    let join_handle = tokio::spawn(async move {
-        axum::serve(
-            listener,
-            router.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .await?;
+    let join_handle = match acceptor {
+        Some(acceptor) => tokio::spawn(tls::serve(listener, acceptor, router)),
+        None => tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await?;
 
-        Ok(())
-    });
+            Ok(())
+        }),
+    };
     Ok(Handle { join_handle })
 }
@@ -0,0 +1,317 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt::{Display, Formatter},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use gradecope_proto::ctl::QueuePosition;
+use sqlx::postgres::PgListener;
+use uuid::Uuid;
+
+use crate::ServerCtx;
+
+/// How the switchboard orders submitted jobs when a runner asks for one.
+///
+/// Every policy yields a total order over the queue, not just the next job, so that the queue can
+/// also be listed in the order it will (probably) be run in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchedulingPolicy {
+    /// Oldest submission first, regardless of who submitted it.
+    Fifo,
+    /// Users take turns: each user's oldest job goes before anyone's second job, and among users'
+    /// n-th jobs, the user who was least recently served goes first.
+    RoundRobin,
+    /// The user who used the least runner time recently goes first, oldest job first; users who
+    /// used the same amount take turns like under round-robin. Older runner time counts for less;
+    /// see `--fair-share-half-life-mins`.
+    FairShare,
+}
+impl Display for SchedulingPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SchedulingPolicy::Fifo => "fifo",
+            SchedulingPolicy::RoundRobin => "round-robin",
+            SchedulingPolicy::FairShare => "fair-share",
+        })
+    }
+}
+
+/// Returns the IDs of submitted jobs in the order the active policy would run them.
+///
+/// If `device_classes` is given, only jobs that can run on one of those device classes are
+/// considered, and likewise for `job_specs`. At most `limit` jobs are returned, or all of them if
+/// `limit` is `None`.
+pub async fn ordered_queue(
+    server_ctx: &ServerCtx,
+    device_classes: Option<&[String]>,
+    job_specs: Option<&[String]>,
+    limit: Option<i64>,
+) -> sqlx::Result<Vec<Uuid>> {
+    let pool = &server_ctx.pool;
+    let ids = match server_ctx.opts.scheduling_policy {
+        SchedulingPolicy::Fifo => {
+            sqlx::query_scalar!(
+                r#"
+                SELECT jobs.id
+                FROM jobs
+                    INNER JOIN job_types ON job_types.id = jobs.job_type
+                WHERE jobs.state = 'submitted'
+                    AND ($1::text[] IS NULL OR job_types.device_class IS NULL
+                        OR job_types.device_class = ANY($1))
+                    AND ($3::text[] IS NULL OR job_types.spec = ANY($3))
+                ORDER BY jobs.submit_timestamp ASC
+                LIMIT $2;
+                "#,
+                device_classes,
+                limit,
+                job_specs,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        SchedulingPolicy::RoundRobin => {
+            sqlx::query_scalar!(
+                r#"
+                SELECT queue.id as "id!"
+                FROM (
+                    SELECT jobs.id, jobs.submit_timestamp,
+                        ROW_NUMBER() OVER (PARTITION BY jobs.owner ORDER BY jobs.submit_timestamp) AS turn,
+                        (SELECT MAX(served.start_timestamp) FROM jobs served
+                            WHERE served.owner = jobs.owner) AS last_served
+                    FROM jobs
+                        INNER JOIN job_types ON job_types.id = jobs.job_type
+                    WHERE jobs.state = 'submitted'
+                        AND ($1::text[] IS NULL OR job_types.device_class IS NULL
+                            OR job_types.device_class = ANY($1))
+                        AND ($3::text[] IS NULL OR job_types.spec = ANY($3))
+                ) queue
+                ORDER BY queue.turn ASC, queue.last_served ASC NULLS FIRST, queue.submit_timestamp ASC
+                LIMIT $2;
+                "#,
+                device_classes,
+                limit,
+                job_specs,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        SchedulingPolicy::FairShare => {
+            let half_life_secs = f64::from(server_ctx.opts.fair_share_half_life_mins) * 60.0;
+            sqlx::query_scalar!(
+                r#"
+                SELECT queue.id as "id!"
+                FROM (
+                    SELECT jobs.id, jobs.submit_timestamp,
+                        ROW_NUMBER() OVER (PARTITION BY jobs.owner ORDER BY jobs.submit_timestamp) AS turn,
+                        COALESCE((
+                            SELECT SUM(
+                                EXTRACT(EPOCH FROM COALESCE(used.stop_timestamp, NOW()::timestamp) - used.start_timestamp)::float8
+                                * POWER(0.5, EXTRACT(EPOCH FROM NOW()::timestamp - used.start_timestamp)::float8 / $3::float8))
+                            FROM jobs used
+                            WHERE used.owner = jobs.owner
+                                AND used.start_timestamp IS NOT NULL
+                                AND used.start_timestamp > NOW()::timestamp - make_interval(secs => $3::float8 * 8)
+                        ), 0) AS usage
+                    FROM jobs
+                        INNER JOIN job_types ON job_types.id = jobs.job_type
+                    WHERE jobs.state = 'submitted'
+                        AND ($1::text[] IS NULL OR job_types.device_class IS NULL
+                            OR job_types.device_class = ANY($1))
+                        AND ($4::text[] IS NULL OR job_types.spec = ANY($4))
+                ) queue
+                ORDER BY queue.usage ASC, queue.turn ASC, queue.submit_timestamp ASC
+                LIMIT $2;
+                "#,
+                device_classes,
+                limit,
+                half_life_secs,
+                job_specs,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+    };
+    Ok(ids)
+}
+
+/// Picks the next job to hand to a runner with idle devices of the given classes, that can run the
+/// given job specs. Jobs the runner is still running are skipped: they can be queued again while
+/// it does, if it was away for too long, but it is yet to stop them.
+pub async fn next_job(
+    server_ctx: &ServerCtx,
+    device_classes: &[String],
+    job_specs: &[String],
+    running: &[Uuid],
+) -> sqlx::Result<Option<Uuid>> {
+    let limit = 1 + running.len() as i64;
+    Ok(ordered_queue(server_ctx, Some(device_classes), Some(job_specs), Some(limit))
+        .await?
+        .into_iter()
+        .find(|job_id| !running.contains(job_id)))
+}
+
+/// How many of a job spec's latest runs its run time is estimated from.
+const RECENT_RUNS: i64 = 20;
+
+/// Where a submitted job stands in the queue, or `None` if it is not submitted (anymore).
+///
+/// The job is ranked among the queued jobs that could take the same devices. Its start is
+/// estimated by handing the jobs ahead of it to the devices that can run it as they free up,
+/// assuming every job takes as long as recent runs of this job's spec did on average.
+pub async fn queue_position(
+    server_ctx: &ServerCtx,
+    job_id: Uuid,
+) -> sqlx::Result<Option<QueuePosition>> {
+    let pool = &server_ctx.pool;
+    let Some(job) = sqlx::query!(
+        r#"
+        SELECT job_types.spec, job_types.device_class
+        FROM jobs
+            INNER JOIN job_types ON job_types.id = jobs.job_type
+        WHERE jobs.id = $1 AND jobs.state = 'submitted';
+        "#,
+        job_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let device_classes = job.device_class.as_ref().map(std::slice::from_ref);
+    let order = ordered_queue(server_ctx, device_classes, None, None).await?;
+    let Some(ahead) = order.iter().position(|id| *id == job_id) else {
+        // started or canceled in the meantime
+        return Ok(None);
+    };
+
+    // devices of draining runners won't take any more jobs
+    let draining: Vec<Uuid> = server_ctx.draining_runners.lock().await.iter().copied().collect();
+    let devices = sqlx::query!(
+        r#"
+        SELECT devices.current_job, running.start_timestamp as "running_since?"
+        FROM devices
+            INNER JOIN runners ON runners.id = devices.runner_id
+            LEFT JOIN jobs running ON running.id = devices.current_job
+        WHERE devices.connected AND devices.quarantine_reason IS NULL
+            AND runners.connected AND $1 = ANY(runners.job_specs)
+            AND NOT (runners.id = ANY($3))
+            AND ($2::text IS NULL OR devices.class = $2);
+        "#,
+        job.spec,
+        job.device_class,
+        &draining,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let run_secs = sqlx::query_scalar!(
+        r#"
+        SELECT AVG(EXTRACT(EPOCH FROM recent.stop_timestamp - recent.start_timestamp))::float8
+        FROM (
+            SELECT jobs.start_timestamp, jobs.stop_timestamp
+            FROM jobs
+                INNER JOIN job_types ON job_types.id = jobs.job_type
+            WHERE job_types.spec = $1 AND jobs.state IN ('completed', 'timeout')
+                AND jobs.start_timestamp IS NOT NULL AND jobs.stop_timestamp IS NOT NULL
+            ORDER BY jobs.stop_timestamp DESC
+            LIMIT $2
+        ) recent;
+        "#,
+        job.spec,
+        RECENT_RUNS,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let now = Utc::now().naive_utc();
+    let estimated_start = run_secs
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .and_then(|run_time| TimeDelta::from_std(run_time).ok())
+        .and_then(|run_time| {
+            let free_at = devices.iter().map(|device| match device.current_job {
+                Some(_) => (device.running_since.unwrap_or(now) + run_time).max(now),
+                None => now,
+            });
+            estimate_start(free_at, ahead, run_time)
+        });
+
+    Ok(Some(QueuePosition {
+        position: u32::try_from(ahead + 1).unwrap_or(u32::MAX),
+        active_devices: u32::try_from(devices.len()).unwrap_or(u32::MAX),
+        estimated_start: estimated_start.map(|t| t.and_utc()),
+    }))
+}
+
+/// When the job behind `ahead` others starts, if devices free up at `free_at` and every job takes
+/// `run_time`. `None` if there are no devices.
+fn estimate_start(
+    free_at: impl IntoIterator<Item = NaiveDateTime>,
+    ahead: usize,
+    run_time: TimeDelta,
+) -> Option<NaiveDateTime> {
+    let mut free_at: BinaryHeap<_> = free_at.into_iter().map(Reverse).collect();
+    for _ in 0..ahead {
+        let Reverse(t) = free_at.pop()?;
+        free_at.push(Reverse(t + run_time));
+    }
+    free_at.peek().map(|Reverse(t)| *t)
+}
+
+/// Postgres channel on which a trigger on `jobs` notifies that a job was queued or should be
+/// canceled.
+const JOBS_CHANGED_CHANNEL: &str = "jobs_changed";
+
+/// Wakes runners waiting for work, through `ServerCtx::jobs_changed`, whenever a job was queued or
+/// should be canceled. Runs forever, reconnecting to the database if it has to.
+pub async fn watch_jobs(server_ctx: Arc<ServerCtx>) {
+    loop {
+        if let Err(e) = listen_for_job_changes(&server_ctx).await {
+            tracing::error!("Stopped listening for job changes: {e}; retrying");
+        }
+        // runners still find out about work when their waits time out in the meantime
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn listen_for_job_changes(server_ctx: &ServerCtx) -> sqlx::Result<()> {
+    let mut listener = PgListener::connect_with(&server_ctx.pool).await?;
+    listener.listen(JOBS_CHANGED_CHANNEL).await?;
+    loop {
+        // Anything may have changed while not listening, so waiters are woken both initially and
+        // whenever the connection had to be re-established (`None`), as well as on notifications
+        server_ctx.jobs_changed.send_replace(());
+        listener.try_recv().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    fn at(mins: i64) -> NaiveDateTime {
+        DateTime::UNIX_EPOCH.naive_utc() + TimeDelta::minutes(mins)
+    }
+
+    #[test]
+    fn no_devices_no_estimate() {
+        assert_eq!(estimate_start([], 0, TimeDelta::minutes(10)), None);
+    }
+
+    #[test]
+    fn jobs_ahead_go_to_whichever_device_frees_up_first() {
+        // one idle device, and one busy for another 5 minutes
+        let free_at = [at(0), at(5)];
+        let run_time = TimeDelta::minutes(10);
+        assert_eq!(estimate_start(free_at, 0, run_time), Some(at(0)));
+        assert_eq!(estimate_start(free_at, 1, run_time), Some(at(5)));
+        assert_eq!(estimate_start(free_at, 2, run_time), Some(at(10)));
+        assert_eq!(estimate_start(free_at, 3, run_time), Some(at(15)));
+        assert_eq!(estimate_start(free_at, 4, run_time), Some(at(20)));
+    }
+}
@@ -12,6 +12,42 @@ pub enum JobState {
     Timeout,
 }
 
+#[derive(Debug, Clone, Copy, sqlx::Type)]
+#[sqlx(type_name = "job_phase", rename_all = "snake_case")]
+pub enum JobPhase {
+    Started,
+    RepoFetched,
+    BuildDone,
+    DeviceFlashed,
+    TestsDone,
+    CleanupDone,
+}
+impl From<gradecope_proto::runner::JobPhase> for JobPhase {
+    fn from(phase: gradecope_proto::runner::JobPhase) -> Self {
+        use gradecope_proto::runner::JobPhase as P;
+        match phase {
+            P::Started => Self::Started,
+            P::RepoFetched => Self::RepoFetched,
+            P::BuildDone => Self::BuildDone,
+            P::DeviceFlashed => Self::DeviceFlashed,
+            P::TestsDone => Self::TestsDone,
+            P::CleanupDone => Self::CleanupDone,
+        }
+    }
+}
+impl From<JobPhase> for gradecope_proto::runner::JobPhase {
+    fn from(phase: JobPhase) -> Self {
+        match phase {
+            JobPhase::Started => Self::Started,
+            JobPhase::RepoFetched => Self::RepoFetched,
+            JobPhase::BuildDone => Self::BuildDone,
+            JobPhase::DeviceFlashed => Self::DeviceFlashed,
+            JobPhase::TestsDone => Self::TestsDone,
+            JobPhase::CleanupDone => Self::CleanupDone,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SqlUser {
     pub id: Uuid,
@@ -1,9 +1,9 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 
 use futures::FutureExt as _;
 use futures_concurrency::future::Race as _;
 use tokio::{
-    io::{AsyncReadExt as _, AsyncWriteExt as _}, net::UnixStream, sync::oneshot, task::JoinHandle, time::timeout,
+    io::{AsyncReadExt as _, AsyncWriteExt as _}, net::UnixStream, sync::{Mutex, oneshot}, task::JoinHandle, time::timeout,
 };
 use uuid::Uuid;
 use gradecope_proto::submit::Submission;
@@ -13,31 +13,57 @@ use crate::sql::JobState;
 struct SubmissionListener {
     #[allow(unused)]
     user: SqlUser,
+    socket_path: PathBuf,
     cancel_notifier: oneshot::Sender<()>,
     join_handle: JoinHandle<()>,
 }
+impl SubmissionListener {
+    /// Stops listening, and removes the socket file.
+    async fn stop(self) {
+        let _ = self.cancel_notifier.send(());
+        let abort_handle = self.join_handle.abort_handle();
+        if tokio::time::timeout(Duration::from_millis(10), self.join_handle)
+            .await
+            .is_err()
+        {
+            abort_handle.abort();
+        }
+        if let Err(e) = tokio::fs::remove_file(&self.socket_path).await {
+            tracing::warn!("Unable to remove socket at {}: {e}", self.socket_path.display());
+        }
+    }
+}
+
+/// Per-user submission socket listeners, keyed by user name. Listeners can be added and removed
+/// while the switchboard is running; see [`spawn_socket_listener`].
+#[derive(Default)]
 pub struct SubmissionListenerSet {
-    active_listeners: boxcar::Vec<SubmissionListener>,
+    active_listeners: Mutex<HashMap<String, SubmissionListener>>,
 }
 impl SubmissionListenerSet {
-    fn from_listeners(active_listeners: boxcar::Vec<SubmissionListener>) -> Self {
-        Self { active_listeners }
+    pub async fn contains(&self, user_name: &str) -> bool {
+        self.active_listeners.lock().await.contains_key(user_name)
     }
-    pub async fn close(self) {
-        for listener in self.active_listeners.into_iter() {
-            let _ = listener.cancel_notifier.send(());
-            let abort_handle = listener.join_handle.abort_handle();
-            if let Err(_) =
-                tokio::time::timeout(Duration::from_millis(10), listener.join_handle).await
-            {
-                abort_handle.abort();
-            }
+    /// Stops the listener for the given user and removes their socket file. Returns whether there
+    /// was a listener to stop.
+    pub async fn remove(&self, user_name: &str) -> bool {
+        let Some(listener) = self.active_listeners.lock().await.remove(user_name) else {
+            return false;
+        };
+        listener.stop().await;
+        true
+    }
+    /// Stops every listener and removes their socket files.
+    pub async fn close(&self) {
+        let listeners = std::mem::take(&mut *self.active_listeners.lock().await);
+        for (_, listener) in listeners {
+            listener.stop().await;
         }
     }
 }
 
 #[derive(Debug, thiserror::Error)]
-enum SubmitError {
+pub enum SubmitError {
     #[error("Too many active jobs: {active} (user quota is {max})")]
     Quota {
         active: u32,
@@ -52,6 +78,156 @@ enum SubmitError {
     Internal,
     #[error("Invalid job type: {spec}")]
     InvalidSpec { spec: String },
+    #[error("Job type {spec} is not accepting submissions")]
+    DisabledSpec { spec: String },
+    #[error("No such commit in your repository, or the hash is ambiguous: {commit}")]
+    NoSuchCommit { commit: String },
+    #[error("The switchboard is shutting down; try again once it is back up")]
+    ShuttingDown,
+}
+
+/// Resolves `commit`, a full or abbreviated hash of a commit in `user`'s repository, to the
+/// commit's full hash, so that the job doesn't depend on what an abbreviation means later on.
+pub async fn resolve_commit(
+    server_ctx: &ServerCtx,
+    user: &SqlUser,
+    commit: &str,
+) -> Result<String, SubmitError> {
+    // Only accept hex so that nothing the user sends can be interpreted as a git option or revision
+    // expression.
+    if !(4..=64).contains(&commit.len()) || !commit.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Err(SubmitError::NoSuchCommit { commit: commit.to_owned() })?
+    }
+
+    let repo = server_ctx
+        .opts
+        .home_prefix
+        .join(&user.name)
+        .join(&server_ctx.opts.repo_path);
+    // The repository belongs to the student, not to us, so git needs to be told to trust it.
+    // Ambiguous abbreviations fail to resolve, like ones that match nothing.
+    let output = tokio::process::Command::new("git")
+        .arg("-c")
+        .arg("safe.directory=*")
+        .arg("-C")
+        .arg(&repo)
+        .arg("rev-parse")
+        .arg("--verify")
+        .arg("--quiet")
+        .arg(format!("{commit}^{{commit}}"))
+        .stderr(std::process::Stdio::null())
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => {
+            let hash = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+            if hash.is_empty() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                tracing::error!("git rev-parse in {repo} printed {hash:?}", repo = repo.display());
+                return Err(SubmitError::Internal);
+            }
+            Ok(hash)
+        }
+        Ok(_) => Err(SubmitError::NoSuchCommit { commit: commit.to_owned() }),
+        Err(e) => {
+            tracing::error!("Failed to run git in {repo}: {e}", repo = repo.display());
+            Err(SubmitError::Internal)
+        }
+    }
+}
+
+/// Validates a submission against the job types and the user's quotas, and if it passes, adds it to
+/// the job queue. Shared by the push-triggered submission sockets and the `submit` RPC on the ctl
+/// socket.
+pub async fn enqueue_job(
+    server_ctx: &ServerCtx,
+    user: &SqlUser,
+    commit: &str,
+    spec: &str,
+) -> Result<Uuid, SubmitError> {
+    if server_ctx.shutting_down() {
+        Err(SubmitError::ShuttingDown)?
+    }
+
+    let job_type_id = match sqlx::query!(
+        "SELECT job_types.id, job_types.enabled FROM job_types WHERE job_types.spec = $1 LIMIT 1;",
+        spec
+    )
+        .fetch_one(&server_ctx.pool)
+        .await
+    {
+        Ok(t) if !t.enabled => {
+            tracing::debug!("Job spec {spec} is disabled");
+            Err(SubmitError::DisabledSpec { spec: spec.to_owned() })?
+        }
+        Ok(t) => t.id,
+        Err(e) => {
+            tracing::warn!("No such job spec {spec}: {e:?}");
+            Err(SubmitError::InvalidSpec { spec: spec.to_owned() })?
+        }
+    };
+
+    let active_jobs = match sqlx::query!(
+        "SELECT COUNT(*) FROM jobs WHERE owner = $1 AND state IN ('submitted', 'started')",
+        user.id,
+    ).fetch_one(&server_ctx.pool)
+        .await {
+        Ok(t) => {
+            t.count.unwrap_or(0)
+        }
+        Err(e) => {
+            tracing::error!("Failed to fetch active job count: {e}");
+            Err(SubmitError::Internal)?
+        }
+    };
+    if active_jobs < 0 {
+        tracing::error!("Absurd value for active job count: {active_jobs}");
+        Err(SubmitError::Internal)?
+    }
+
+    if active_jobs >= i64::from(server_ctx.opts.quota_max_concurrent_jobs) {
+        tracing::debug!("User reached concurrent job count quota");
+        Err(SubmitError::Quota { active: active_jobs.try_into().unwrap_or(u32::MAX), max: server_ctx.opts.quota_max_concurrent_jobs })?
+    }
+
+    let jobs_last_hour = match sqlx::query!(
+        r#"SELECT COUNT(*) FROM "jobs" WHERE owner = $1 AND submit_timestamp >= NOW() - INTERVAL '1 HOUR';"#,
+        user.id
+    ).fetch_one(&server_ctx.pool).await {
+        Ok(t) => t.count.unwrap_or(0),
+        Err(e) => {
+            tracing::error!("Failed to fetch number of submitted jobs in last hour: {e}");
+            Err(SubmitError::Internal)?
+        }
+    };
+    if jobs_last_hour >= i64::from(server_ctx.opts.quota_jobs_per_hr) {
+        tracing::debug!("User reached per-hour job quota");
+        Err(SubmitError::TimeQuota { count: jobs_last_hour.try_into().unwrap_or(u32::MAX), max: server_ctx.opts.quota_jobs_per_hr })?
+    }
+
+    let job_id = Uuid::from_u128(rand::random());
+
+    match sqlx::query!(
+        r#"
+        INSERT INTO jobs (id, owner, job_type, commit, state, submit_timestamp)
+        VALUES ($1, $2, $3, $4, $5, now());
+        "#,
+        job_id,
+        user.id,
+        job_type_id,
+        commit,
+        JobState::Submitted as JobState,
+    )
+        .execute(&server_ctx.pool)
+        .await {
+        Ok(_) => (),
+        Err(e) => {
+            tracing::error!("Failed to insert job: {e}");
+            Err(SubmitError::Internal)?
+        }
+    }
+
+    tracing::info!("Enqueued job {job_id} ({spec} @ {commit}) for user {user}");
+    Ok(job_id)
 }
 
 #[tracing::instrument(fields(user = %user), skip(user, stream, server_ctx))]
@@ -100,84 +276,7 @@ async fn accept_submission(
 
         tracing::debug!("Received submission {submission:?}");
 
-        let job_type_id = match sqlx::query!(
-            "SELECT job_types.id FROM job_types WHERE job_types.spec = $1 LIMIT 1;",
-            submission.spec
-        )
-            .fetch_one(&server_ctx.pool)
-            .await
-        {
-            Ok(t) => t.id,
-            Err(e) => {
-                tracing::warn!(
-                    "In submission {submission:?}, no such job spec {spec}: {e:?}",
-                    spec = submission.spec
-                );
-                Err(SubmitError::InvalidSpec { spec: submission.spec.clone() })?
-            }
-        };
-
-        let active_jobs = match sqlx::query!(
-            "SELECT COUNT(*) FROM jobs WHERE owner = $1 AND (state <> 'completed' OR state <> 'canceled')",
-            user.id,
-        ).fetch_one(&server_ctx.pool)
-            .await {
-            Ok(t) => {
-                t.count.unwrap_or(0)
-            }
-            Err(e) => {
-                tracing::error!("Failed to fetch active job count: {e}");
-                Err(SubmitError::Internal)?
-            }
-        };
-        if active_jobs < 0 {
-            tracing::error!("Absurd value for active job count: {active_jobs}");
-            Err(SubmitError::Internal)?
-        }
-
-        if active_jobs >= i64::from(server_ctx.opts.quota_max_concurrent_jobs) {
-            tracing::debug!("User reached concurrent job count quota");
-            Err(SubmitError::Quota { active: active_jobs.try_into().unwrap_or(u32::MAX), max: server_ctx.opts.quota_max_concurrent_jobs })?
-        }
-
-        let jobs_last_hour = match sqlx::query!(
-            r#"SELECT COUNT(*) FROM "jobs" WHERE owner = $1 AND submit_timestamp >= NOW() - INTERVAL '1 HOUR';"#,
-            user.id
-        ).fetch_one(&server_ctx.pool).await {
-            Ok(t) => t.count.unwrap_or(0),
-            Err(e) => {
-                tracing::error!("Failed to fetch number of submitted jobs in last hour: {e}");
-                Err(SubmitError::Internal)?
-            }
-        };
-        if jobs_last_hour >= i64::from(server_ctx.opts.quota_jobs_per_hr) {
-            tracing::debug!("User reached per-hour job quota");
-            Err(SubmitError::TimeQuota { count: jobs_last_hour.try_into().unwrap_or(u32::MAX), max: server_ctx.opts.quota_jobs_per_hr })?
-        }
-
-        let job_id = Uuid::from_u128(rand::random());
-
-        match sqlx::query!(
-            r#"
-            INSERT INTO jobs (id, owner, job_type, commit, state, submit_timestamp)
-            VALUES ($1, $2, $3, $4, $5, now());
-            "#,
-            job_id,
-            user.id,
-            job_type_id,
-            submission.commit,
-            JobState::Submitted as JobState,
-        )
-            .execute(&server_ctx.pool)
-            .await {
-            Ok(_) => (),
-            Err(e) => {
-                tracing::error!("Failed to insert job: {e}");
-                Err(SubmitError::Internal)?
-            }
-        }
-
-        job_id
+        enqueue_job(server_ctx, user, &submission.commit, &submission.spec).await?
     };
 
     match res {
@@ -194,86 +293,153 @@ async fn accept_submission(
     Ok(())
 }
 
-pub async fn spawn_socket_listeners(
-    server_ctx: Arc<ServerCtx>,
-) -> eyre::Result<SubmissionListenerSet> {
-    let users = sqlx::query_as!(SqlUser, r#"SELECT * FROM "users";"#)
+/// Spawns submission socket listeners for every user in the database.
+///
+/// Users whose socket can't be set up (e.g. because their home directory is missing) are logged
+/// and skipped, so that one broken account doesn't keep everyone else from submitting.
+pub async fn spawn_socket_listeners(server_ctx: Arc<ServerCtx>) -> eyre::Result<()> {
+    let users = sqlx::query_as!(SqlUser, r#"SELECT id, name FROM "users";"#)
+        .fetch_all(&server_ctx.pool)
+        .await?;
+
+    tracing::debug!("Spawning submission socket listeners for {} users", users.len());
+
+    for user in users {
+        if let Err(e) = spawn_socket_listener(server_ctx.clone(), user.clone()).await {
+            tracing::error!("Skipping submission socket for user {user}: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes the set of listeners match the `users` table: spawns listeners for users that don't have
+/// one yet, and closes listeners for users that no longer exist. Returns the number of listeners
+/// that were spawned.
+pub async fn sync_socket_listeners(server_ctx: Arc<ServerCtx>) -> eyre::Result<usize> {
+    let users = sqlx::query_as!(SqlUser, r#"SELECT id, name FROM "users";"#)
         .fetch_all(&server_ctx.pool)
         .await?;
 
-    println!("{users:?}");
+    let listeners = &server_ctx.submit_listeners;
+    let stale: Vec<String> = {
+        let active = listeners.active_listeners.lock().await;
+        active
+            .keys()
+            .filter(|name| !users.iter().any(|u| &&u.name == name))
+            .cloned()
+            .collect()
+    };
+    for name in stale {
+        listeners.remove(&name).await;
+    }
 
-    let listeners = boxcar::vec![];
+    let mut spawned = 0;
     for user in users {
-        let user_homedir = server_ctx.opts.home_prefix.join(&user.name);
-        if !user_homedir.is_dir() {
+        if listeners.contains(&user.name).await {
+            continue;
+        }
+        match spawn_socket_listener(server_ctx.clone(), user.clone()).await {
+            Ok(()) => spawned += 1,
+            Err(e) => tracing::error!("Skipping submission socket for user {user}: {e}"),
+        }
+    }
+
+    Ok(spawned)
+}
+
+/// Binds `user`'s submission socket and starts accepting submissions on it, replacing any listener
+/// that already exists for that user.
+pub async fn spawn_socket_listener(server_ctx: Arc<ServerCtx>, user: SqlUser) -> eyre::Result<()> {
+    let user_homedir = server_ctx.opts.home_prefix.join(&user.name);
+    if !user_homedir.is_dir() {
+        tracing::error!(
+            "Expected user {user_name} to have home directory {home_dir} but {home_dir} does not exist",
+            user_name = user.name,
+            home_dir = user_homedir.display()
+        );
+        eyre::bail!(
+            "no such directory: {home_dir}",
+            home_dir = user_homedir.display()
+        );
+    }
+    let socket_path = user_homedir.join(&server_ctx.opts.submit_socket_path);
+
+    // Held until the new listener is in place, so that a concurrent call for the same user can't
+    // replace it without stopping it.
+    let mut active_listeners = server_ctx.submit_listeners.active_listeners.lock().await;
+
+    // Close the old listener first so that it doesn't hold on to the socket we're about to bind.
+    // Stopping it removes the socket file, so this can't wait until the new one is bound.
+    if let Some(listener) = active_listeners.remove(&user.name) {
+        listener.stop().await;
+    }
+
+    if socket_path.exists()
+        && let Err(e) = tokio::fs::remove_file(&socket_path).await
+    {
+        tracing::error!("Unable to remove old socket at {}: {e}", socket_path.display());
+        Err(e)?;
+    }
+    let socket_listener = match tokio::net::UnixListener::bind(&socket_path) {
+        Ok(t) => t,
+        Err(e) => {
             tracing::error!(
-                "Expected user {user_name} to have home directory {home_dir} but {home_dir} does not exist",
-                user_name = user.name,
-                home_dir = user_homedir.display()
-            );
-            eyre::bail!(
-                "no such directory: {home_dir}",
-                home_dir = user_homedir.display()
+                "Failed to bind to socket {socket_path}: {e:?}",
+                socket_path = socket_path.display()
             );
+            Err(e)?
         }
-        let socket_path = user_homedir.join(&server_ctx.opts.submit_socket_path);
-        if socket_path.exists() {
-            if let Err(e) = tokio::fs::remove_file(&socket_path).await {
-                tracing::error!("Unable to remove old socket at {}: {e}", socket_path.display());
-                Err(e)?;
-            }
-        }
-        let socket_listener = match tokio::net::UnixListener::bind(&socket_path) {
-            Ok(t) => t,
-            Err(e) => {
-                tracing::error!(
-                    "Failed to bind to socket {socket_path}: {e:?}",
-                    socket_path = socket_path.display()
-                );
-                Err(e)?
+    };
+    let (cancel_notifier, mut cancel_receiver) = oneshot::channel();
+    let user2 = user.clone();
+    let server_ctx2 = server_ctx.clone();
+    let socket_path2 = socket_path.clone();
+    let join_handle = tokio::spawn(async move {
+        let socket_path = socket_path2;
+        loop {
+            enum Branch<T> {
+                Accept(T),
+                Cancel,
             }
-        };
-        let (cancel_notifier, mut cancel_receiver) = oneshot::channel();
-        let user2 = user.clone();
-        let server_ctx2 = server_ctx.clone();
-        let join_handle = tokio::spawn(async move {
-            loop {
-                enum Branch<T> {
-                    Accept(T),
-                    Cancel,
-                }
-                let raced = (
-                    socket_listener.accept().map(Branch::Accept),
-                    (&mut cancel_receiver).map(|_| Branch::Cancel),
-                )
-                    .race()
-                    .await;
-                match raced {
-                    Branch::Accept(Ok((stream, _remote_addr))) => {
-                        if let Err(e) = accept_submission(&server_ctx2, &user2, stream).await {
-                            tracing::warn!(
-                                "Unable to process submission from socket {socket_path}: {e}",
-                                socket_path = socket_path.display()
-                            );
-                        }
-                    }
-                    Branch::Accept(Err(e)) => {
-                        tracing::error!(
-                            "Failed to accept stream from socket {socket_path}: {e:?}",
+            let raced = (
+                socket_listener.accept().map(Branch::Accept),
+                (&mut cancel_receiver).map(|_| Branch::Cancel),
+            )
+                .race()
+                .await;
+            match raced {
+                Branch::Accept(Ok((stream, _remote_addr))) => {
+                    if let Err(e) = accept_submission(&server_ctx2, &user2, stream).await {
+                        tracing::warn!(
+                            "Unable to process submission from socket {socket_path}: {e}",
                             socket_path = socket_path.display()
                         );
                     }
-                    Branch::Cancel => break,
                 }
+                Branch::Accept(Err(e)) => {
+                    tracing::error!(
+                        "Failed to accept stream from socket {socket_path}: {e:?}",
+                        socket_path = socket_path.display()
+                    );
+                }
+                Branch::Cancel => break,
             }
-        });
-        listeners.push(SubmissionListener {
+        }
+    });
+    tracing::info!(
+        "Listening for submissions from {user} on {socket_path}",
+        socket_path = socket_path.display()
+    );
+    active_listeners.insert(
+        user.name.clone(),
+        SubmissionListener {
             user,
+            socket_path,
             cancel_notifier,
             join_handle,
-        });
-    }
+        },
+    );
 
-    Ok(SubmissionListenerSet::from_listeners(listeners))
+    Ok(())
 }
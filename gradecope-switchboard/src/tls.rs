@@ -0,0 +1,263 @@
+//! Mutual TLS between the switchboard and runners. Runners present a certificate signed by the
+//! switchboard's CA, and are identified by the common name in it.
+
+use std::{
+    fs::OpenOptions,
+    io::Write as _,
+    net::SocketAddr,
+    os::unix::fs::OpenOptionsExt as _,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{Extension, extract::ConnectInfo};
+use gradecope_proto::tls::load_certs;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject as _};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+/// The name in the certificate a runner connected with, which it must register under.
+#[derive(Debug, Clone)]
+pub struct RunnerCertificate(pub String);
+
+/// TLS configuration for the runner server: presents `cert` and `key`, and only accepts runners
+/// with a certificate signed by `ca`.
+pub fn acceptor(ca: &Path, cert: &Path, key: &Path) -> eyre::Result<TlsAcceptor> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut roots = rustls::RootCertStore::empty();
+    for ca_cert in load_certs(ca)? {
+        roots.add(ca_cert)?;
+    }
+    let client_verifier =
+        rustls::server::WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()?;
+
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| eyre::eyre!("failed to read private key from {}: {e}", key.display()))?;
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(load_certs(cert)?, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The common name in the subject of a certificate.
+fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(name.to_owned())
+}
+
+/// Serves `router` to runners over mutual TLS. Like with `axum::serve`, requests carry the
+/// runner's address as `ConnectInfo`, and additionally the name in its certificate as a
+/// [`RunnerCertificate`].
+pub async fn serve(listener: TcpListener, acceptor: TlsAcceptor, router: axum::Router) -> eyre::Result<()> {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(t) => t,
+            Err(e) => {
+                // e.g. out of file descriptors, which may well pass
+                tracing::error!("Failed to accept runner connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let router = router.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!("TLS handshake with {peer_addr} failed: {e}");
+                    return;
+                }
+            };
+            let Some(name) = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(common_name)
+            else {
+                tracing::warn!("Runner at {peer_addr} presented a certificate without a common name");
+                return;
+            };
+            let router = router
+                .layer(Extension(ConnectInfo::<SocketAddr>(peer_addr)))
+                .layer(Extension(RunnerCertificate(name)));
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(router))
+                .await
+            {
+                tracing::debug!("Connection from {peer_addr} ended with error: {e}");
+            }
+        });
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct GenCertsOpts {
+    /// Directory to write the keypairs to. A CA already in it (`ca.pem` and `ca.key`) is used to
+    /// sign the new certificates; otherwise a new one is generated.
+    #[arg(long)]
+    out_dir: PathBuf,
+    /// Host names or IP addresses runners reach the switchboard at. If given, a keypair for the
+    /// switchboard is generated (`switchboard.pem` and `switchboard.key`).
+    #[arg(long = "server-name")]
+    server_names: Vec<String>,
+    /// Names of runners to generate keypairs for (`runner-<name>.pem` and `runner-<name>.key`),
+    /// which they must use as their `--id`.
+    #[arg(long = "runner")]
+    runners: Vec<String>,
+}
+
+/// Writes `contents` to a new file at `path`, readable only by its owner if it is `private`.
+fn write_new(path: &Path, contents: &str, private: bool) -> eyre::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(if private { 0o600 } else { 0o644 })
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| eyre::eyre!("failed to write {}: {e}", path.display()))?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+/// Generates the keypairs for mutual TLS between the switchboard and runners: the switchboard
+/// gets its own keypair and the CA certificate, and each runner its own keypair and the CA
+/// certificate.
+pub fn generate(opts: &GenCertsOpts) -> eyre::Result<()> {
+    std::fs::create_dir_all(&opts.out_dir)?;
+    let ca_cert_path = opts.out_dir.join("ca.pem");
+    let ca_key_path = opts.out_dir.join("ca.key");
+
+    let ca = if ca_cert_path.exists() {
+        println!("Using CA in {}", ca_cert_path.display());
+        let key = KeyPair::from_pem(&std::fs::read_to_string(&ca_key_path)?)?;
+        Issuer::from_ca_cert_pem(&std::fs::read_to_string(&ca_cert_path)?, key)?
+    } else {
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, "gradecope CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let cert = params.self_signed(&key)?;
+        write_new(&ca_key_path, &key.serialize_pem(), true)?;
+        write_new(&ca_cert_path, &cert.pem(), false)?;
+        Issuer::new(params, key)
+    };
+
+    let mut leaves = vec![];
+    if !opts.server_names.is_empty() {
+        let mut params = CertificateParams::new(opts.server_names.clone())?;
+        params.distinguished_name.push(DnType::CommonName, "gradecope-switchboard");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        leaves.push(("switchboard".to_owned(), params));
+    }
+    for runner in &opts.runners {
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, runner.as_str());
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        leaves.push((format!("runner-{runner}"), params));
+    }
+
+    for (name, params) in leaves {
+        let cert_path = opts.out_dir.join(format!("{name}.pem"));
+        let key_path = opts.out_dir.join(format!("{name}.key"));
+        // keypairs that are already deployed stay valid
+        if cert_path.exists() || key_path.exists() {
+            println!("Keypair {name} already exists, leaving it alone");
+            continue;
+        }
+        let key = KeyPair::generate()?;
+        let cert = params.signed_by(&key, &ca)?;
+        write_new(&key_path, &key.serialize_pem(), true)?;
+        write_new(&cert_path, &cert.pem(), false)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::pki_types::ServerName;
+
+    use super::*;
+
+    /// A new directory with a CA, a keypair for a switchboard at `localhost`, and one for each of
+    /// `runners`.
+    fn generate_certs(runners: &[&str]) -> PathBuf {
+        let out_dir = std::env::temp_dir().join(format!("gradecope-tls-{}", uuid::Uuid::new_v4()));
+        generate(&GenCertsOpts {
+            out_dir: out_dir.clone(),
+            server_names: vec!["localhost".to_owned()],
+            runners: runners.iter().map(|runner| runner.to_string()).collect(),
+        })
+        .unwrap();
+        out_dir
+    }
+
+    /// Connects to a switchboard with the keypair in `server_dir` as the runner `name` with the
+    /// keypair in `runner_dir`, and returns the name the switchboard got from its certificate.
+    async fn handshake(server_dir: &Path, runner_dir: &Path, name: &str) -> eyre::Result<Option<String>> {
+        let acceptor = acceptor(
+            &server_dir.join("ca.pem"),
+            &server_dir.join("switchboard.pem"),
+            &server_dir.join("switchboard.key"),
+        )?;
+
+        let mut roots = rustls::RootCertStore::empty();
+        for ca_cert in load_certs(&server_dir.join("ca.pem"))? {
+            roots.add(ca_cert)?;
+        }
+        let key = PrivateKeyDer::from_pem_file(runner_dir.join(format!("runner-{name}.key")))?;
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(load_certs(&runner_dir.join(format!("runner-{name}.pem")))?, key)?;
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+        let (_client, server) = tokio::join!(
+            connector.connect(ServerName::try_from("localhost")?, client),
+            acceptor.accept(server),
+        );
+        Ok(server?
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(common_name))
+    }
+
+    #[tokio::test]
+    async fn runners_are_known_by_their_common_name() {
+        let dir = generate_certs(&["pi-1"]);
+        let name = handshake(&dir, &dir, "pi-1").await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(name.unwrap().as_deref(), Some("pi-1"));
+    }
+
+    #[tokio::test]
+    async fn runners_signed_by_another_ca_are_rejected() {
+        let dir = generate_certs(&[]);
+        let other_dir = generate_certs(&["pi-1"]);
+        let name = handshake(&dir, &other_dir, "pi-1").await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&other_dir).unwrap();
+        assert!(name.is_err(), "{name:?}");
+    }
+}
@@ -92,6 +92,10 @@ fi
   sudo addgroup "${GRADECOPE_STUDENTS_GROUP}"
   sudo usermod -aG "${GRADECOPE_STUDENTS_GROUP}"
 
+  # Members of this group may use the switchboard's admin socket (`gradecope-ctl admin ...`).
+  sudo addgroup "${GRADECOPE_ADMINS_GROUP}"
+  sudo usermod -aG "${GRADECOPE_ADMINS_GROUP}" "${GRADECOPE_SWITCHBOARD_USER}"
+
   # -----------------------------------------------------------------------------------------------
   # Postgres initialization
 
@@ -10,8 +10,11 @@ GRANT ALL ON SCHEMA public TO public;
 CREATE TABLE users (
     id UUID PRIMARY KEY NOT NULL,
     name TEXT NOT NULL,
+    /* late days the user may spend over all labs; NULL for the switchboard's default */
+    late_day_budget INTEGER NULL DEFAULT NULL,
 
-    UNIQUE (name)
+    UNIQUE (name),
+    CHECK( late_day_budget IS NULL OR late_day_budget >= 0 )
 );
 
 /* Types of jobs.
@@ -20,7 +23,19 @@ CREATE TABLE job_types (
     id UUID NOT NULL PRIMARY KEY,
     spec TEXT NOT NULL,
 
-    UNIQUE (spec)
+    /* how long the .run.sh script may run before it is killed */
+    run_timeout_secs INTEGER NOT NULL DEFAULT 120,
+    /* how long the .cleanup.sh script may run before it is killed */
+    cleanup_timeout_secs INTEGER NOT NULL DEFAULT 45,
+    /* how much of the run log is kept */
+    max_log_bytes INTEGER NOT NULL DEFAULT 65536,
+    /* if set, only devices of this class may run the job */
+    device_class TEXT NULL DEFAULT NULL,
+    /* whether new submissions are accepted */
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+
+    UNIQUE (spec),
+    CHECK( run_timeout_secs > 0 AND cleanup_timeout_secs > 0 AND max_log_bytes > 0 )
 );
 
 /* Job states.
@@ -88,18 +103,49 @@ CREATE TABLE jobs (
         NULL
         DEFAULT NULL,
 
+    /* log streamed by the runner while the job is started; replaced by run_log once it stops */
+    live_log
+        BYTEA
+        NULL
+        DEFAULT NULL,
+
     /* the reason the job was canceled */
     cancel_reason
         TEXT
         NULL
         DEFAULT NULL,
 
+    /* set while the job is started to ask the runner to kill it; becomes cancel_reason once the
+       runner reports back */
+    cancel_request
+        TEXT
+        NULL
+        DEFAULT NULL,
+
     /* result of the test */
     test_result
         TEXT
         NULL
         DEFAULT NULL,
 
+    /* the runner connection the job was handed to; kept after the job stops */
+    runner_id
+        UUID
+        NULL
+        DEFAULT NULL,
+
+    /* number of times the job was put back in the queue after its runner went away */
+    requeue_count
+        INTEGER
+        NOT NULL
+        DEFAULT 0,
+
+    /* why the job ended in error, if the switchboard rather than the test script decided that */
+    error_reason
+        TEXT
+        NULL
+        DEFAULT NULL,
+
     /* ------------ CHECK CONSTRAINTS ------------ */
 
     /* if state is started or finished, then start_timestamp is not null */
@@ -117,8 +163,237 @@ CREATE TABLE jobs (
         NOT( state = 'submitted' OR state = 'started' )
         OR run_log IS NULL ),
     /* state is canceled IFF canceled_timestamp is not null */
-    CHECK( ( state = 'canceled' OR state = 'completed' OR state = 'completed' OR state = 'error' )
+    CHECK( ( state = 'canceled' OR state = 'completed' OR state = 'timeout' OR state = 'error' )
                = ( stop_timestamp IS NOT NULL ) ),
     CHECK( ( state = 'canceled' ) = ( cancel_reason IS NOT NULL ) ),
+    /* only a started job has a live log */
+    CHECK( NOT( state <> 'started' ) OR live_log IS NULL ),
+    /* a started job always belongs to a runner */
+    CHECK( NOT( state = 'started' ) OR runner_id IS NOT NULL ),
+    /* cancellation can only be requested for a running job */
+    CHECK( NOT( cancel_request IS NOT NULL ) OR state <> 'submitted' ),
     CHECK( ( state = 'completed' ) = ( test_result IS NOT NULL ) )
 );
+
+/* Wakes the switchboard's runner connections whenever a job is queued, or a started job should be
+   canceled, so that runners don't have to poll for work */
+CREATE FUNCTION notify_jobs_changed() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('jobs_changed', NEW.id::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER jobs_changed
+    AFTER INSERT OR UPDATE OF state, cancel_request ON jobs
+    FOR EACH ROW
+    WHEN ( NEW.state = 'submitted' OR NEW.cancel_request IS NOT NULL )
+    EXECUTE FUNCTION notify_jobs_changed();
+
+/* Per-test-case results, as reported by a job's test script through its results file
+ */
+CREATE TABLE test_cases (
+    job_id
+        UUID
+        NOT NULL
+        REFERENCES jobs(id)
+        ON DELETE CASCADE,
+    /* position of the test case in the results file */
+    idx
+        INTEGER
+        NOT NULL,
+    name
+        TEXT
+        NOT NULL,
+    passed
+        BOOLEAN
+        NOT NULL,
+    points
+        DOUBLE PRECISION
+        NULL
+        DEFAULT NULL,
+    max_points
+        DOUBLE PRECISION
+        NULL
+        DEFAULT NULL,
+    message
+        TEXT
+        NULL
+        DEFAULT NULL,
+
+    PRIMARY KEY (job_id, idx)
+);
+
+/* Phases of a job's run; see `gradecope_proto::runner::JobPhase`.
+ */
+CREATE TYPE job_phase AS ENUM(
+    'started',
+    'repo_fetched',
+    'build_done',
+    'device_flashed',
+    'tests_done',
+    'cleanup_done'
+);
+
+/* When a job reached each phase of its latest run, by its runner's clock
+ */
+CREATE TABLE job_phases (
+    job_id
+        UUID
+        NOT NULL
+        REFERENCES jobs(id)
+        ON DELETE CASCADE,
+    phase
+        job_phase
+        NOT NULL,
+    timestamp
+        TIMESTAMP WITHOUT TIME ZONE
+        NOT NULL,
+
+    PRIMARY KEY (job_id, phase)
+);
+
+/* Labs, which students are checked off on by passing the lab's job type
+ */
+CREATE TABLE labs (
+    id
+        UUID
+        NOT NULL
+        PRIMARY KEY,
+    name
+        TEXT
+        NOT NULL,
+    job_type
+        UUID
+        NOT NULL
+        REFERENCES job_types(id),
+
+    /* when the lab becomes visible to students */
+    release_timestamp
+        TIMESTAMP WITHOUT TIME ZONE
+        NOT NULL,
+    /* checkoffs submitted after this are late; NULL if the lab has no deadline */
+    deadline_timestamp
+        TIMESTAMP WITHOUT TIME ZONE
+        NULL
+        DEFAULT NULL,
+
+    UNIQUE (name),
+    UNIQUE (job_type),
+    CHECK( deadline_timestamp IS NULL OR deadline_timestamp >= release_timestamp )
+);
+
+/* Per-user deadlines that replace a lab's deadline
+ */
+CREATE TABLE extensions (
+    user_id
+        UUID
+        NOT NULL
+        REFERENCES users(id)
+        ON DELETE CASCADE,
+    lab_id
+        UUID
+        NOT NULL
+        REFERENCES labs(id)
+        ON DELETE CASCADE,
+    deadline_timestamp
+        TIMESTAMP WITHOUT TIME ZONE
+        NOT NULL,
+
+    PRIMARY KEY (user_id, lab_id)
+);
+
+/* Runners that have registered with the switchboard, connected or not
+ */
+CREATE TABLE runners (
+    /* derived from the runner's name, see `gradecope_proto::runner::runner_id` */
+    id
+        UUID
+        NOT NULL
+        PRIMARY KEY,
+    name
+        TEXT
+        NOT NULL,
+    version
+        TEXT
+        NOT NULL,
+    /* job specs the runner has test scripts for */
+    job_specs
+        TEXT[]
+        NOT NULL,
+    connected
+        BOOLEAN
+        NOT NULL,
+    /* address the runner last connected from */
+    peer_addr
+        TEXT
+        NOT NULL,
+    /* last registration or heartbeat */
+    last_seen_timestamp
+        TIMESTAMP WITHOUT TIME ZONE
+        NOT NULL,
+    jobs_run
+        INTEGER
+        NOT NULL
+        DEFAULT 0,
+    jobs_errored
+        INTEGER
+        NOT NULL
+        DEFAULT 0,
+
+    UNIQUE (name)
+);
+
+/* Devices attached to runners
+ */
+CREATE TABLE devices (
+    /* derived from the runner's name and the device's USB port */
+    id
+        UUID
+        NOT NULL
+        PRIMARY KEY,
+    runner_id
+        UUID
+        NOT NULL
+        REFERENCES runners(id)
+        ON DELETE CASCADE,
+    serial
+        TEXT
+        NOT NULL,
+    usb_bus
+        INTEGER
+        NOT NULL,
+    usb_ports
+        INTEGER[]
+        NOT NULL,
+    class
+        TEXT
+        NULL
+        DEFAULT NULL,
+    /* whether the device was attached when its runner last registered, and the runner is still
+       connected */
+    connected
+        BOOLEAN
+        NOT NULL,
+    current_job
+        UUID
+        NULL
+        DEFAULT NULL
+        REFERENCES jobs(id)
+        ON DELETE SET NULL,
+    /* why the runner took the device out of service; NULL if it is in service */
+    quarantine_reason
+        TEXT
+        NULL
+        DEFAULT NULL,
+    jobs_run
+        INTEGER
+        NOT NULL
+        DEFAULT 0,
+    jobs_errored
+        INTEGER
+        NOT NULL
+        DEFAULT 0,
+
+    CHECK( connected OR current_job IS NULL )
+);
@@ -12,10 +12,10 @@ source "${SELF_PATH}/style.sh"
 source "${SELF_PATH}/config.sh"
 
 # -------------------------------------------------------------------------------------------------
-# Database helper
+# Switchboard admin helper
 
-@pg-run () {
-  psql -d "${GRADECOPE_SWITCHBOARD_USER}" -q -c "$1"
+@admin-run () {
+  gradecope-ctl admin "$@"
 }
 
 # -------------------------------------------------------------------------------------------------
@@ -51,7 +51,7 @@ fi
   sudo usermod -rG "${STUDENT}" "${GRADECOPE_SWITCHBOARD_USER}"
   sudo usermod -rG "${STUDENT}" "${GRADECOPE_RUNNER_USER}"
   sudo deluser --remove-home "${STUDENT}"
-  @pg-run "DELETE FROM users WHERE name = '${STUDENT}';"
+  @admin-run remove-user "${STUDENT}" 2> /dev/null
   exit 1
 }
   
@@ -148,26 +148,17 @@ sudo chmod g+x "/home/${STUDENT}/gradecope-sockets"
 
 # -------------------------------------------------------------------------------------------------
 # If everything else succeeded, add to gradecope database
+#
+# This goes through the running switchboard's admin socket, which also binds the user's submission
+# socket, so no restart is needed.
 
-# user id doesn't really matter
-UUID=$(uuid -v 4)
-
-if ! @pg-run "INSERT INTO users (id, name) VALUES ('${UUID}', '$STUDENT');" ; then
+if ! @admin-run add-user "${STUDENT}" ; then
   echo -e "${FERR}: failed to add user ${STUSR}${STUDENT}${RST} to database"
   @cleanup
 else
   echo -e "Added user ${STUSR}${STUDENT}${RST} to database"
 fi
 
-# -------------------------------------------------------------------------------------------------
-# Notify currently running instance, if it exists
-#
-# TODO: what's the protocol?
-
-#{"jsonrpc": "2.0", "method": "add-user", "params": {"user": "${STUDENT}"}}
-cat <<- HEREDOC | socat - "UNIX-CONNECT:~${GRADECOPE_SWITCHBOARD_USER}/${GRADECOPE_SOCKETS_DIR}/admin.sock"
-HEREDOC
-
 # -------------------------------------------------------------------------------------------------
 # Get SSH ready
 #
@@ -8,10 +8,20 @@ source "${SELF_DIR}/config.env"
 # Set umask so sockets are group-writable (students can access ctl socket)
 umask 002
 
+# Runners can only connect from elsewhere once they have to authenticate; see gen-runner-mtls-certs.sh
+if [[ -f "${GRADECOPE_CERTS_DIR}/switchboard.pem" ]] ; then
+  RUNNER_SERVER_ARGS="--bind-server 0.0.0.0:${GRADECOPE_RUNNER_PORT} \
+    --tls-ca ${GRADECOPE_CERTS_DIR}/ca.pem \
+    --tls-cert ${GRADECOPE_CERTS_DIR}/switchboard.pem \
+    --tls-key ${GRADECOPE_CERTS_DIR}/switchboard.key"
+else
+  RUNNER_SERVER_ARGS="--bind-server 127.0.0.1:${GRADECOPE_RUNNER_PORT}"
+fi
+
 COMMAND="$(cat <<HEREDOC
 env PGDATABASE="${GRADECOPE_DATABASE}" RUST_LOG="gradecope=debug" \
   cargo run --bin gradecope-switchboard -- \
-    --bind-server 127.0.0.1:${GRADECOPE_RUNNER_PORT}
+    ${RUNNER_SERVER_ARGS}
 HEREDOC
 )"
 sg "${GRADECOPE_STUDENTS_GROUP}" "${COMMAND}"
\ No newline at end of file
@@ -8,9 +8,16 @@ export SPEC="$2"
 export REMOTE_REPO_PATH="$3"
 export COMMIT="$4"
 export LOGFILE_PATH="$5"
+# The device's serial port, or with --capture-console a pty that passes data to and from it
 export DEVICE_SERIAL="$6"
 export USB_PORT="$7"
 export USB_HUB_PATH="$8"
+# Optional per-test results, as a JSON array of
+# {"name": str, "passed": bool, "points": num?, "max_points": num?, "message": str?}
+export RESULTS_PATH="$9"
+# Append a line with one of repo_fetched, build_done or device_flashed whenever the job gets
+# through that phase, so that its timings show up in `gradecope-ctl status`
+export PHASES_PATH="${10}"
 
 # ENV: GRADECOPE_SWITCHBOARD_SERVER
 # ENV: GRADECOPE_SWITCHBOARD_RUNNER_USER
@@ -70,6 +77,7 @@ echo | @log
 echo "Updating repo @ ${LOCAL_REPO_PATH}" | @log
 mkdir -p "${LOCAL_REPO_PATH}"
 @update-repo | @log
+echo repo_fetched >> "$PHASES_PATH"
 
 # Get pi-install proxy ready
 
@@ -78,24 +86,35 @@ PROXY_PI_INSTALL="${PATH_ADD}/pi-install"
 ORIGINAL_PI_INSTALL="$(which pi-install)"
 cat <<EOF > "${PROXY_PI_INSTALL}"
 #!/bin/bash
-if [[ "$#" -ne 1 ]] ; then
-  echo "failed to forward pi-install to ${DEVICE_SERIAL}: expected exactly one argument, got ${#}"
+if [[ "\$#" -ne 1 ]] ; then
+  echo "failed to forward pi-install to ${DEVICE_SERIAL}: expected exactly one argument, got \${#}"
   exit 1
 fi
-"${ORIGINAL_PI_INSTALL}" "${DEVICE_SERIAL}" $1
+# make check builds everything before it flashes the first program, so the build is done by the
+# time it first gets here
+echo build_done >> "${PHASES_PATH}"
+"${ORIGINAL_PI_INSTALL}" "${DEVICE_SERIAL}" "\$1"
+STATUS=\$?
+# pi-install only exits once the program it flashed has run, which is the first the script can tell
+# that the device took it; only the first flash is kept as the phase
+if [[ "\$STATUS" -eq 0 ]] ; then
+  echo device_flashed >> "${PHASES_PATH}"
+fi
+exit "\$STATUS"
 EOF
 chmod +x "${PROXY_PI_INSTALL}"
 export PATH="${PATH_ADD}:${PATH}"
 
 echo "Installed proxied pi-install to ${PROXY_PI_INSTALL}" | @log
 
-# Reboot USB device
-
-echo "Cycling power to Pi" | @log
-
-uhubctl -a cycle -p $USB_PORT -l $USB_HUB_PATH | @log
-
 cd "${LOCAL_REPO_PATH}/labs/1-trusting-trust"
 make clean
 make check
-exit
+STATUS=$?
+# make check only fails without ever flashing the device if the submission doesn't build, which is
+# the submission's fault rather than the device's, so it fails the job instead of erroring it
+if [[ "$STATUS" -ne 0 ]] && ! grep -qsx build_done "$PHASES_PATH" ; then
+  echo "Build failed" | @log
+  exit 1
+fi
+exit "$STATUS"
@@ -0,0 +1,25 @@
+#!/bin/bash
+
+# Invoked like the job scripts, but with empty job arguments, after the runner has power cycled the
+# device. Exits 0 if the device is fit to run jobs; otherwise, the last line written to the log
+# file is reported as the reason.
+
+export WORKER_ID="$1"
+export LOGFILE_PATH="$5"
+export DEVICE_SERIAL="$6"
+export USB_PORT="$7"
+export USB_HUB_PATH="$8"
+
+exec >> "${LOGFILE_PATH}" 2>&1
+
+# Wait for its serial port to come back
+
+for _ in $(seq 20) ; do
+  if [[ -c "${DEVICE_SERIAL}" ]] ; then
+    exit 0
+  fi
+  sleep 0.5
+done
+
+echo "${DEVICE_SERIAL} did not come back after power cycle"
+exit 1